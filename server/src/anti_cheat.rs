@@ -0,0 +1,212 @@
+use crate::ball::MAX_CLIENT_SPIN;
+use crate::config::{env_or, env_secs_or};
+use crate::player::SeqNr;
+use std::time::Duration;
+
+/// Thresholds for flagging players.
+pub struct AntiCheatConfig {
+    /// Minimum number of moves before the ratios are checked.
+    pub min_moves: u32,
+    /// Maximum ratio of moves that needed clamping.
+    pub max_clamp_ratio: f32,
+    /// Minimum number of claimed hits before the hit ratio is checked.
+    pub min_claimed_hits: u32,
+    /// Minimum ratio of claimed hits that the server could confirm.
+    pub min_confirmed_hit_ratio: f32,
+    /// A sequence number jump larger than this is suspicious.
+    pub max_seq_nr_jump: SeqNr,
+    /// Maximum number of suspicious sequence number jumps.
+    pub max_seq_nr_jumps: u32,
+    /// Maximum number of impossible spin values.
+    pub max_impossible_spins: u32,
+    /// Longest time a late hit may be in the past, however slow the connection.
+    pub max_rewind: Duration,
+    /// Kick flagged players instead of only logging them.
+    pub kick: bool,
+}
+
+impl AntiCheatConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            min_moves: env_or("PINGBONG_ANTI_CHEAT_MIN_MOVES", 200),
+            max_clamp_ratio: env_or("PINGBONG_ANTI_CHEAT_MAX_CLAMP_RATIO", 0.2),
+            min_claimed_hits: env_or("PINGBONG_ANTI_CHEAT_MIN_CLAIMED_HITS", 10),
+            min_confirmed_hit_ratio: env_or("PINGBONG_ANTI_CHEAT_MIN_CONFIRMED_HIT_RATIO", 0.3),
+            // 5 seconds of frames.
+            max_seq_nr_jump: env_or("PINGBONG_ANTI_CHEAT_MAX_SEQ_NR_JUMP", 60 * 5),
+            max_seq_nr_jumps: env_or("PINGBONG_ANTI_CHEAT_MAX_SEQ_NR_JUMPS", 5),
+            max_impossible_spins: env_or("PINGBONG_ANTI_CHEAT_MAX_IMPOSSIBLE_SPINS", 10),
+            max_rewind: env_secs_or("PINGBONG_ANTI_CHEAT_MAX_REWIND", 0.4),
+            kick: env_or("PINGBONG_ANTI_CHEAT_KICK", false),
+        }
+    }
+}
+
+/// Reason for flagging a player.
+#[derive(Debug, Copy, Clone)]
+pub enum Violation {
+    /// Too many moves were faster than allowed.
+    ClampFrequency { clamped: u32, moves: u32 },
+    /// Too many hits were claimed that the server could not confirm.
+    UnconfirmedHits { claimed: u32, confirmed: u32 },
+    /// Too many large jumps in the sequence numbers.
+    SeqNrJumps(u32),
+    /// Too many spin values that the client can't produce.
+    ImpossibleSpins(u32),
+}
+
+/// Per-player move statistics.
+#[derive(Default)]
+pub struct MoveStats {
+    moves: u32,
+    clamped_moves: u32,
+    claimed_hits: u32,
+    confirmed_hits: u32,
+    seq_nr_jumps: u32,
+    impossible_spins: u32,
+    flagged: bool,
+}
+
+impl MoveStats {
+    /// Records a move.
+    pub fn record_move(
+        &mut self,
+        clamped: bool,
+        seq_nr_jump: SeqNr,
+        spin: f32,
+        config: &AntiCheatConfig,
+    ) {
+        self.moves += 1;
+        if clamped {
+            self.clamped_moves += 1;
+        }
+        if seq_nr_jump > config.max_seq_nr_jump {
+            self.seq_nr_jumps += 1;
+        }
+        // Moves are clamped to this spin, a real client never goes over it.
+        if !spin.is_finite() || spin.abs() > MAX_CLIENT_SPIN {
+            self.impossible_spins += 1;
+        }
+    }
+
+    /// Gets the number of accepted moves.
+    #[cfg(test)]
+    pub fn moves(&self) -> u32 {
+        self.moves
+    }
+
+    /// Records a hit claimed by the client.
+    #[inline]
+    pub fn record_claimed_hit(&mut self) {
+        self.claimed_hits += 1;
+    }
+
+    /// Records a claimed hit that the server confirmed.
+    #[inline]
+    pub fn record_confirmed_hit(&mut self) {
+        self.confirmed_hits += 1;
+    }
+
+    /// Checks the statistics against the thresholds.
+    /// Only returns a violation the first time the player goes over a threshold.
+    pub fn check(&mut self, config: &AntiCheatConfig) -> Option<Violation> {
+        if self.flagged {
+            return None;
+        }
+
+        let violation = if self.moves >= config.min_moves
+            && self.clamped_moves as f32 > self.moves as f32 * config.max_clamp_ratio
+        {
+            Some(Violation::ClampFrequency {
+                clamped: self.clamped_moves,
+                moves: self.moves,
+            })
+        } else if self.claimed_hits >= config.min_claimed_hits
+            && (self.confirmed_hits as f32)
+                < self.claimed_hits as f32 * config.min_confirmed_hit_ratio
+        {
+            Some(Violation::UnconfirmedHits {
+                claimed: self.claimed_hits,
+                confirmed: self.confirmed_hits,
+            })
+        } else if self.seq_nr_jumps > config.max_seq_nr_jumps {
+            Some(Violation::SeqNrJumps(self.seq_nr_jumps))
+        } else if self.impossible_spins > config.max_impossible_spins {
+            Some(Violation::ImpossibleSpins(self.impossible_spins))
+        } else {
+            None
+        };
+
+        self.flagged = violation.is_some();
+        violation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ball::SPIN_MAX;
+
+    fn test_config() -> AntiCheatConfig {
+        AntiCheatConfig {
+            min_moves: 10,
+            max_clamp_ratio: 0.2,
+            min_claimed_hits: 4,
+            min_confirmed_hit_ratio: 0.5,
+            max_seq_nr_jump: 100,
+            max_seq_nr_jumps: 2,
+            max_impossible_spins: 2,
+            max_rewind: Duration::from_millis(400),
+            kick: false,
+        }
+    }
+
+    #[test]
+    fn honest_player_is_not_flagged() {
+        let config = test_config();
+        let mut stats = MoveStats::default();
+        for i in 0..100 {
+            stats.record_move(i % 10 == 0, 3, SPIN_MAX, &config);
+        }
+        for i in 0..10 {
+            stats.record_claimed_hit();
+            if i % 3 != 0 {
+                stats.record_confirmed_hit();
+            }
+        }
+        assert!(stats.check(&config).is_none());
+    }
+
+    #[test]
+    fn unconfirmed_hits_are_flagged_once() {
+        let config = test_config();
+        let mut stats = MoveStats::default();
+        for _ in 0..4 {
+            stats.record_claimed_hit();
+        }
+        stats.record_confirmed_hit();
+        assert!(matches!(
+            stats.check(&config),
+            Some(Violation::UnconfirmedHits {
+                claimed: 4,
+                confirmed: 1
+            })
+        ));
+        assert!(stats.check(&config).is_none());
+    }
+
+    #[test]
+    fn nan_and_large_spins_are_impossible() {
+        let config = test_config();
+        let mut stats = MoveStats::default();
+        stats.record_move(false, 3, MAX_CLIENT_SPIN, &config);
+        for spin in [f32::NAN, MAX_CLIENT_SPIN * 1.5, -MAX_CLIENT_SPIN * 1.5] {
+            stats.record_move(false, 3, spin, &config);
+        }
+        assert!(matches!(
+            stats.check(&config),
+            Some(Violation::ImpossibleSpins(3))
+        ));
+    }
+}
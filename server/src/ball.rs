@@ -2,7 +2,7 @@ use crate::circular_buffer::CircularBuffer;
 use crate::player::{ClientId, Player};
 use crate::powerup::{PowerUp, PowerUpEffect};
 use crate::protocol::BallData;
-use crate::room::{CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH};
+use crate::room::{TickRate, CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH};
 use crate::util::clampf32;
 use crate::vector::Vector;
 use serde::Serialize;
@@ -10,26 +10,50 @@ use std::cell::RefCell;
 use std::collections::hash_map::Values;
 use std::iter::Filter;
 
-pub const MOVEMENT_BUFFER_CAP: usize = 6;
+/// Ball states kept for late collisions, enough for the highest tick rate.
+pub const MOVEMENT_BUFFER_CAP: usize = TickRate::High.history_len();
 pub const DEFAULT_BALL_SPEED: f32 = 4.0;
 pub const BALL_RADIUS: f32 = 8.0;
 pub const BALL_RADIUS_ANGLE: f32 = 0.031_989_083; //(BALL_RADIUS / CIRCLE_RADIUS).atan();
 pub const SPIN_MAX: f32 = 0.05;
+/// Largest spin a client move may carry, it can turn the spin completely to the other direction.
+pub const MAX_CLIENT_SPIN: f32 = SPIN_MAX * 2.0;
 pub const NO_TEAM: u8 = 0b1111;
 const MAX_RALLIES: u8 = 5;
+/// Radius of the shield wall behind a team, just behind the players.
+const SHIELD_RADIUS: f32 = CIRCLE_RADIUS + 32.0;
 
 type PlayerIter<'a, 'b> =
     Filter<Values<'a, ClientId, RefCell<Player>>, &'b dyn Fn(&&RefCell<Player>) -> bool>;
 
 #[derive(Copy, Clone)]
-pub struct RoomDataForBall {
+pub struct RoomDataForBall<'a> {
     pub delta: f32,
     pub team_count: u32,
-    pub power_up: Option<PowerUp>,
+    pub power_ups: &'a [PowerUp],
     pub spin_towards_center: bool,
+    pub speed_factor: f32,
+    /// Bitmask of the teams that have a shield wall behind them.
+    pub shielded_teams: u8,
 }
 
-#[derive(Serialize, Copy, Clone)]
+impl RoomDataForBall<'_> {
+    /// Calculates which team owns the sector the (centered) position is in.
+    fn sector_team(&self, centered_pos: Vector) -> u8 {
+        let team_angle = std::f32::consts::PI * 2.0 / (self.team_count as f32);
+        // +2*PI needed because otherwise negative value modulo issues.
+        let angle = centered_pos.angle() + std::f32::consts::PI * 2.0;
+        (((angle / team_angle) as u32) % self.team_count) as u8
+    }
+
+    /// Checks if the team has a shield wall.
+    #[inline]
+    fn is_shielded(&self, team_nr: u8) -> bool {
+        self.shielded_teams & (1 << team_nr) != 0
+    }
+}
+
+#[derive(Debug, Serialize, Copy, Clone, Default, PartialEq, Eq)]
 pub struct HitPair(u8);
 
 impl HitPair {
@@ -200,7 +224,7 @@ impl Ball {
         };
 
         let new_dir = Self::calculate_direction_modification(last.base.dir, spin, room_data.delta);
-        let new = last.base.pos + new_dir * room_data.delta;
+        let new = last.base.pos + new_dir * (room_data.delta * room_data.speed_factor);
         let newh = new - Vector::new(FIELD_WIDTH / 2.0, FIELD_HEIGHT / 2.0);
 
         // Player collision checking.
@@ -232,14 +256,17 @@ impl Ball {
         // Check for power-up collision.
         let mut power_up_effect = None;
         if last.hit_pair.hit_team() != NO_TEAM {
-            if let Some(power_up) = room_data.power_up {
-                if power_up.collides(last.base.pos, new) {
-                    debug!("Power-up collision!");
-                    power_up_effect = Some(PowerUpEffect {
-                        effect_type: power_up.effect_type(),
-                        activating_team: last.hit_pair.hit_team(),
-                    });
-                }
+            if let Some(power_up) = room_data
+                .power_ups
+                .iter()
+                .find(|p| p.collides(last.base.pos, new))
+            {
+                debug!("Power-up collision!");
+                power_up_effect = Some(PowerUpEffect {
+                    power_up_id: power_up.id(),
+                    effect_type: power_up.effect_type(),
+                    activating_team: last.hit_pair.hit_team(),
+                });
             }
         }
 
@@ -299,17 +326,26 @@ impl Ball {
         } else {
             let mut history = generate_clean_history();
 
+            // Check for a shield wall, only when moving outwards to avoid double bounces.
+            if room_data.shielded_teams != 0
+                && newh.len_sqr() > SHIELD_RADIUS * SHIELD_RADIUS
+                && newh.dot(new_dir) > 0.0
+                && room_data.is_shielded(room_data.sector_team(newh))
+            {
+                let n = newh.normalized();
+                history.base.pos = last.base.pos;
+                history.base.dir = new_dir - n * (2.0 * n.dot(new_dir));
+                return (BallTickResult::Bounce, power_up_effect, history);
+            }
+
             const THRESHOLD: f32 = CIRCLE_RADIUS + 125.0;
             // Check if outside the circle.
             let btr = if newh.len_sqr() > THRESHOLD * THRESHOLD {
                 if last.hit_pair.receiving_team() == NO_TEAM {
                     // Register losing team such that a sharp course of the ball will not
                     // cause the wrong team to lose.
-                    let team_angle = std::f32::consts::PI * 2.0 / (room_data.team_count as f32);
-                    // +2*PI needed because otherwise negative value modulo issues.
-                    let angle = newh.angle() + std::f32::consts::PI * 2.0;
-                    let team = ((angle / team_angle) as u32) % room_data.team_count;
-                    history.hit_pair = HitPair::new(history.hit_pair.hit_team(), team as u8);
+                    let team = room_data.sector_team(newh);
+                    history.hit_pair = HitPair::new(history.hit_pair.hit_team(), team);
                 }
 
                 BallTickResult::Outside
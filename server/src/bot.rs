@@ -1,7 +1,7 @@
 use crate::ball::{Ball, BALL_RADIUS_ANGLE, NO_TEAM};
 use crate::player::{ClientId, Player, SeqNr};
 use crate::protocol::ClientMoveUpdate;
-use crate::room::{CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH, TPF, TPS};
+use crate::room::{TickRate, CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH};
 use crate::util::positive_angle_wrap;
 use crate::vector::Vector;
 use smallvec::SmallVec;
@@ -68,9 +68,10 @@ impl Bot {
         balls: &[Ball],
         //team_data: &TeamData,
         ball_mask: BallMask,
+        tick_rate: TickRate,
     ) -> BotTickResult {
         let seq_nr = self.seq_nr.get();
-        self.seq_nr.set(seq_nr + TPF);
+        self.seq_nr.set(seq_nr + tick_rate.frames_per_tick());
 
         // 1. Calculate where on the circle the balls will end up.
         // 2. Filter those that are outside our range.
@@ -95,7 +96,7 @@ impl Bot {
                 let next_dir = Ball::calculate_direction_modification(
                     normalized_position_direction,
                     c.spin,
-                    1.0 / TPS as f32,
+                    1.0 / tick_rate.ticks_per_sec() as f32,
                 );
                 let r = position_direction.len();
                 (
@@ -156,11 +157,11 @@ impl Bot {
         const MARGIN: f32 = 3.0 / 180.0 * std::f32::consts::PI;
         let action = best.1 - MARGIN - player_start_pos;
 
-        // See player.js.
+        // See player.js, the spin decays once per client frame.
         const SPIN_ALPHA: f32 = 1.0 / 4.0;
         const SPIN_DECAY: f32 = 0.8;
-        self.previous_spin
-            .update(|spin| spin * (SPIN_DECAY * SPIN_DECAY * SPIN_DECAY));
+        let decay = SPIN_DECAY.powi(tick_rate.frames_per_tick() as i32);
+        self.previous_spin.update(|spin| spin * decay);
         let spin = self.previous_spin.get() * (1.0 - SPIN_ALPHA) + SPIN_ALPHA * action * 2.0;
 
         BotTickResult {
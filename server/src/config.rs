@@ -0,0 +1,110 @@
+use crate::anti_cheat::AntiCheatConfig;
+use crate::directory::DirectoryConfig;
+use crate::http::HttpConfig;
+use crate::matchmaking::QuickPlayConfig;
+use crate::proxy::ProxyConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::tls::TlsConfig;
+use chrono::Local;
+use lazy_static::lazy_static;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Server configuration, read from `PINGBONG_*` environment variables.
+pub struct Config {
+    pub anti_cheat: AntiCheatConfig,
+    pub directory: DirectoryConfig,
+    pub http: HttpConfig,
+    pub rate_limit: RateLimitConfig,
+    pub proxy: ProxyConfig,
+    pub quick_play: QuickPlayConfig,
+    pub timeouts: TimeoutConfig,
+    pub tls: TlsConfig,
+}
+
+/// Connection timeouts.
+pub struct TimeoutConfig {
+    /// Time to complete the websocket handshake.
+    pub handshake: Duration,
+    /// Time to pick a valid name after connecting.
+    pub name_selection: Duration,
+    /// Time a client may stay silent in the lobby.
+    pub lobby_idle: Duration,
+    /// Interval between pings to players in a room.
+    pub ping_interval: Duration,
+    /// A player in a room is considered gone if nothing was received for this long.
+    pub ping_timeout: Duration,
+    /// A client whose outbox stays full for this long is disconnected.
+    pub max_lag: Duration,
+}
+
+lazy_static! {
+    static ref CONFIG: Config = Config::from_env();
+}
+
+/// Gets the config.
+#[inline]
+pub fn config() -> &'static Config {
+    &CONFIG
+}
+
+/// Reads an environment variable, or uses the default if it's missing or invalid.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            println!(
+                "[{}] invalid value for {}, using the default",
+                Local::now().format("%d-%m %H:%M"),
+                name
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Reads a duration in seconds from an environment variable, or uses the default.
+pub fn env_secs_or(name: &str, default: f32) -> Duration {
+    let secs = env_or(name, default);
+    if secs.is_finite() && secs > 0.0 {
+        Duration::from_secs_f32(secs)
+    } else {
+        println!(
+            "[{}] invalid value for {}, using the default",
+            Local::now().format("%d-%m %H:%M"),
+            name
+        );
+        Duration::from_secs_f32(default)
+    }
+}
+
+impl Config {
+    /// Reads the config from the environment.
+    fn from_env() -> Self {
+        Self {
+            anti_cheat: AntiCheatConfig::from_env(),
+            directory: DirectoryConfig::from_env(),
+            http: HttpConfig::from_env(),
+            rate_limit: RateLimitConfig::from_env(),
+            proxy: ProxyConfig::from_env(),
+            quick_play: QuickPlayConfig::from_env(),
+            timeouts: TimeoutConfig::from_env(),
+            tls: TlsConfig::from_env(),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// Reads the config from the environment.
+    fn from_env() -> Self {
+        Self {
+            handshake: env_secs_or("PINGBONG_HANDSHAKE_TIMEOUT", 10.0),
+            name_selection: env_secs_or("PINGBONG_NAME_TIMEOUT", 30.0),
+            lobby_idle: env_secs_or("PINGBONG_LOBBY_IDLE_TIMEOUT", 300.0),
+            ping_interval: env_secs_or("PINGBONG_PING_INTERVAL", 2.0),
+            ping_timeout: env_secs_or("PINGBONG_PING_TIMEOUT", 6.0),
+            max_lag: env_secs_or("PINGBONG_MAX_LAG", 5.0),
+        }
+    }
+}
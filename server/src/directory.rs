@@ -0,0 +1,183 @@
+use crate::config::env_or;
+use chrono::Local;
+use fnv::FnvHashMap;
+use futures::future::{ready, BoxFuture, FutureExt};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Settings for running several server processes behind one lobby.
+pub struct DirectoryConfig {
+    /// Address clients use to reach this process, sent to clients that are redirected here.
+    pub node_address: String,
+    /// Directory shared with the other processes, rooms are only known locally if empty.
+    pub path: String,
+}
+
+impl DirectoryConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            node_address: env_or("PINGBONG_NODE_ADDRESS", String::new()),
+            path: env_or("PINGBONG_ROOM_DIRECTORY", String::new()),
+        }
+    }
+
+    /// Opens the room directory.
+    pub fn open(&self) -> Arc<dyn RoomDirectory> {
+        if self.path.is_empty() {
+            return Arc::new(MemoryDirectory::default());
+        }
+        if self.node_address.is_empty() {
+            println!(
+                "[{}] PINGBONG_ROOM_DIRECTORY is set without PINGBONG_NODE_ADDRESS, redirects won't work",
+                Local::now().format("%d-%m %H:%M")
+            );
+        }
+        Arc::new(FileDirectory::new(&self.path))
+    }
+}
+
+/// Knows which server process owns a room code.
+/// Nodes are identified by the address clients use to reach them.
+pub trait RoomDirectory: Send + Sync {
+    /// Claims a room code for a node. Returns false if the code is already taken.
+    fn claim<'a>(&'a self, code: &'a str, node: &'a str) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Releases a room code.
+    fn release<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Finds the node that owns a room code.
+    fn lookup<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<String>>>;
+}
+
+/// Directory for a single process, or for nodes sharing memory in tests.
+#[derive(Default)]
+pub struct MemoryDirectory {
+    rooms: Mutex<FnvHashMap<String, String>>,
+}
+
+impl RoomDirectory for MemoryDirectory {
+    fn claim<'a>(&'a self, code: &'a str, node: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        let mut rooms = self.rooms.lock().expect("directory lock");
+        let claimed = !rooms.contains_key(code);
+        if claimed {
+            rooms.insert(code.to_owned(), node.to_owned());
+        }
+        ready(Ok(claimed)).boxed()
+    }
+
+    fn release<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.rooms.lock().expect("directory lock").remove(code);
+        ready(Ok(())).boxed()
+    }
+
+    fn lookup<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<String>>> {
+        let node = self
+            .rooms
+            .lock()
+            .expect("directory lock")
+            .get(code)
+            .cloned();
+        ready(Ok(node)).boxed()
+    }
+}
+
+/// Directory in a folder shared by the nodes, one file per room with the owner in it.
+/// Claims are atomic: the owner is written to a temporary file first, which is then hard linked
+/// to the code, and linking fails if the code exists.
+/// A node that crashes leaves its codes claimed, they are never handed out again.
+pub struct FileDirectory {
+    path: PathBuf,
+}
+
+impl FileDirectory {
+    /// Uses the given folder, it's created on the first claim.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl RoomDirectory for FileDirectory {
+    fn claim<'a>(&'a self, code: &'a str, node: &'a str) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            fs::create_dir_all(&self.path).await?;
+            // Starts with a dot, so it's never a valid code.
+            let temp = self
+                .path
+                .join(format!(".{}-{:x}", code, rand::random::<u64>()));
+            let linked = async {
+                let mut file = fs::File::create(&temp).await?;
+                file.write_all(node.as_bytes()).await?;
+                file.sync_all().await?;
+                fs::hard_link(&temp, self.path.join(code)).await
+            }
+            .await;
+            let _ = fs::remove_file(&temp).await;
+            match linked {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+
+    fn release<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            match fs::remove_file(self.path.join(code)).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+
+    fn lookup<'a>(&'a self, code: &'a str) -> BoxFuture<'a, io::Result<Option<String>>> {
+        async move {
+            match fs::read_to_string(self.path.join(code)).await {
+                // Older nodes could leave an empty file when writing the owner failed.
+                Ok(node) if node.is_empty() => Ok(None),
+                Ok(node) => Ok(Some(node)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[tokio::test]
+    async fn nodes_share_a_file_directory() {
+        let path = env::temp_dir().join(format!("pingbong-directory-{}", process::id()));
+        let (a, b) = (FileDirectory::new(&path), FileDirectory::new(&path));
+
+        assert!(a.claim("T48W1GVJ", "wss://a.example").await.unwrap());
+        assert!(!b.claim("T48W1GVJ", "wss://b.example").await.unwrap());
+        assert!(b.claim("F37AYEB2", "wss://b.example").await.unwrap());
+        assert_eq!(
+            b.lookup("T48W1GVJ").await.unwrap().as_deref(),
+            Some("wss://a.example")
+        );
+
+        a.release("T48W1GVJ").await.unwrap();
+        a.release("T48W1GVJ").await.unwrap();
+        assert_eq!(b.lookup("T48W1GVJ").await.unwrap(), None);
+
+        // Only the codes are left in the directory, and an empty code has no owner.
+        let files = std::fs::read_dir(&path).unwrap().count();
+        assert_eq!(files, 1);
+        std::fs::write(path.join("W1GVJF37"), "").unwrap();
+        assert_eq!(b.lookup("W1GVJF37").await.unwrap(), None);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
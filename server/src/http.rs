@@ -0,0 +1,222 @@
+use crate::config::env_or;
+use crate::rooms;
+use crate::stream::{Rewind, ServerStream};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_HEADERS: usize = 32;
+
+/// Settings for serving plain HTTP next to the websockets.
+pub struct HttpConfig {
+    /// Answer plain HTTP requests instead of dropping them.
+    pub enabled: bool,
+    /// Directory with the built client, static files are disabled if empty.
+    pub root: String,
+}
+
+impl HttpConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            enabled: env_or("PINGBONG_HTTP", false),
+            root: env_or("PINGBONG_HTTP_ROOT", String::new()),
+        }
+    }
+}
+
+/// A parsed request head.
+struct Request {
+    method: String,
+    path: String,
+    upgrade: bool,
+}
+
+/// Reads the request head and answers plain HTTP requests.
+/// Returns the stream if the request is a websocket upgrade, the head will be read again.
+pub async fn route(
+    mut stream: ServerStream,
+    config: &HttpConfig,
+) -> io::Result<Option<ServerStream>> {
+    let mut head = Vec::with_capacity(1024);
+    let request = loop {
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&chunk[..n]);
+
+        if let Some(request) = parse_head(&head)? {
+            break request;
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+    };
+
+    if request.upgrade {
+        return Ok(Some(ServerStream::Rewind(Box::new(Rewind::new(
+            head, stream,
+        )))));
+    }
+
+    let head_only = request.method == "HEAD";
+    let (status, content_type, body) = if request.method != "GET" && !head_only {
+        (
+            "405 Method Not Allowed",
+            "text/plain",
+            b"method not allowed".to_vec(),
+        )
+    } else if request.path == "/healthz" {
+        health()
+    } else {
+        static_file(config, &request.path).await
+    };
+
+    let response_head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(response_head.as_bytes()).await?;
+    if !head_only {
+        stream.write_all(&body).await?;
+    }
+    stream.shutdown().await?;
+    Ok(None)
+}
+
+/// Parses the request head, returns None if it's incomplete.
+fn parse_head(head: &[u8]) -> io::Result<Option<Request>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(httparse::Status::Complete(_)) => {}
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+
+    let upgrade = request.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("upgrade")
+            && header.value.eq_ignore_ascii_case(b"websocket")
+    });
+    Ok(Some(Request {
+        method: request.method.unwrap_or_default().to_string(),
+        path: request.path.unwrap_or_default().to_string(),
+        upgrade,
+    }))
+}
+
+/// Reports the number of rooms, the registry never blocks so the server is always ready.
+fn health() -> (&'static str, &'static str, Vec<u8>) {
+    let rooms = rooms();
+    let body = format!(
+        "{{\"status\":\"ok\",\"rooms\":{},\"playing\":{}}}",
+        rooms.room_count(),
+        rooms.playing_rooms()
+    );
+    ("200 OK", "application/json", body.into_bytes())
+}
+
+/// Serves a file from the root directory.
+/// Unknown paths without an extension get the index, the client does its own routing.
+async fn static_file(config: &HttpConfig, path: &str) -> (&'static str, &'static str, Vec<u8>) {
+    let not_found = ("404 Not Found", "text/plain", b"not found".to_vec());
+    if config.root.is_empty() {
+        return not_found;
+    }
+
+    let path = match resolve(&config.root, path) {
+        Some(path) => path,
+        None => return not_found,
+    };
+
+    let file = if path.is_dir() {
+        path.join("index.html")
+    } else {
+        path
+    };
+    match fs::read(&file).await {
+        Ok(body) => ("200 OK", content_type(&file), body),
+        Err(_) if file.extension().is_none() => {
+            let index = Path::new(&config.root).join("index.html");
+            match fs::read(&index).await {
+                Ok(body) => ("200 OK", content_type(&index), body),
+                Err(_) => not_found,
+            }
+        }
+        Err(_) => not_found,
+    }
+}
+
+/// Maps a request path to a file path inside the root, rejects anything that could escape it.
+fn resolve(root: &str, path: &str) -> Option<PathBuf> {
+    let path = path.split(['?', '#']).next()?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    if path.contains('\\')
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(Path::new(root).join(relative))
+}
+
+/// Guesses the content type from the file extension.
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+        "html" => "text/html; charset=utf-8",
+        "js" => "application/javascript",
+        "css" => "text/css",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "mp3" => "audio/mpeg",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_stays_in_root() {
+        assert_eq!(
+            resolve("build", "/assets/logo.png?v=1"),
+            Some(PathBuf::from("build/assets/logo.png"))
+        );
+        assert_eq!(resolve("build", "/"), Some(PathBuf::from("build")));
+        assert_eq!(resolve("build", "/../secret"), None);
+        assert_eq!(resolve("build", "/assets/../../secret"), None);
+        assert_eq!(
+            resolve("build", "//etc/passwd"),
+            Some(PathBuf::from("build/etc/passwd"))
+        );
+    }
+
+    #[test]
+    fn detects_upgrades() {
+        let head = b"GET / HTTP/1.1\r\nHost: x\r\nUpgrade: WebSocket\r\n\r\n";
+        assert!(parse_head(head).unwrap().unwrap().upgrade);
+        let head = b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n";
+        let request = parse_head(head).unwrap().unwrap();
+        assert!(!request.upgrade);
+        assert_eq!(request.path, "/healthz");
+        assert!(parse_head(b"GET / HTTP/1.1\r\nHost").unwrap().is_none());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::config::config;
+use crate::room::LATENCY_COMPENSATION_WINDOW;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of round trip samples the estimate is based on.
+const RTT_SAMPLES: usize = 5;
+
+/// Added to the round trip time for jitter and the tick the move waited for.
+const REWIND_MARGIN: Duration = Duration::from_millis(60);
+
+/// Shortest rewind window, a hit is always allowed to be a few ticks late.
+const MIN_REWIND: Duration = Duration::from_millis(100);
+
+/// Rewind window until the round trip time is known.
+const DEFAULT_REWIND: Duration = Duration::from_millis(250);
+
+/// Identifies a ping, so a late pong isn't taken for the answer to a newer ping.
+pub type PingToken = u16;
+
+/// Measures the round trip time of a client with application-level pings.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    next_token: PingToken,
+    /// The ping that wasn't answered yet.
+    pending: Option<(PingToken, Instant)>,
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyTracker {
+    /// Starts a ping, an older unanswered ping counts as lost.
+    pub fn ping(&mut self, now: Instant) -> PingToken {
+        let token = self.next_token;
+        self.next_token = self.next_token.wrapping_add(1);
+        self.pending = Some((token, now));
+        token
+    }
+
+    /// Records the answer to a ping. Returns false if it doesn't answer the pending ping.
+    pub fn pong(&mut self, token: PingToken, now: Instant) -> bool {
+        match self.pending {
+            Some((pending, sent)) if pending == token => {
+                self.pending = None;
+                if self.samples.len() == RTT_SAMPLES {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(now.saturating_duration_since(sent));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Gets the round trip time: the median of the recent samples, so a single slow
+    /// (or deliberately delayed) pong doesn't change it.
+    pub fn rtt(&self) -> Option<Duration> {
+        let mut samples = self.samples.iter().copied().collect::<Vec<_>>();
+        samples.sort_unstable();
+        samples.get(samples.len() / 2).copied()
+    }
+
+    /// Gets how far back in time hits of the client are accepted.
+    pub fn rewind_window(&self) -> Duration {
+        match self.rtt() {
+            Some(rtt) => (rtt + REWIND_MARGIN).clamp(MIN_REWIND, max_rewind_window()),
+            None => default_rewind_window(),
+        }
+    }
+}
+
+/// Gets the longest rewind window the server allows, the ball history always covers it.
+fn max_rewind_window() -> Duration {
+    config()
+        .anti_cheat
+        .max_rewind
+        .min(LATENCY_COMPENSATION_WINDOW)
+}
+
+/// Gets the rewind window for players whose round trip time is unknown.
+pub fn default_rewind_window() -> Duration {
+    DEFAULT_REWIND.min(max_rewind_window())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewind_window_follows_the_round_trip_time() {
+        let mut tracker = LatencyTracker::default();
+        assert_eq!(tracker.rtt(), None);
+        assert_eq!(tracker.rewind_window(), DEFAULT_REWIND);
+
+        let mut now = Instant::now();
+        let mut sample = |tracker: &mut LatencyTracker, rtt: u64| {
+            let token = tracker.ping(now);
+            now += Duration::from_millis(rtt);
+            assert!(tracker.pong(token, now));
+        };
+        for _ in 0..RTT_SAMPLES {
+            sample(&mut tracker, 40);
+        }
+        assert_eq!(tracker.rewind_window(), MIN_REWIND);
+
+        // One slow pong changes nothing, a slow connection gets a longer window.
+        sample(&mut tracker, 900);
+        assert_eq!(tracker.rtt(), Some(Duration::from_millis(40)));
+        for _ in 0..RTT_SAMPLES {
+            sample(&mut tracker, 250);
+        }
+        assert_eq!(
+            tracker.rewind_window(),
+            Duration::from_millis(250) + REWIND_MARGIN
+        );
+
+        // But never longer than the server allows.
+        for _ in 0..RTT_SAMPLES {
+            sample(&mut tracker, 2000);
+        }
+        assert_eq!(tracker.rewind_window(), config().anti_cheat.max_rewind);
+
+        // Answers to lost or unknown pings don't count.
+        let token = tracker.ping(now);
+        tracker.ping(now);
+        assert!(!tracker.pong(token, now));
+    }
+}
@@ -1,59 +1,117 @@
 #![feature(map_first_last)]
 #![feature(cell_update)]
+#![cfg_attr(test, feature(test))]
 #![allow(clippy::new_without_default)]
 #![allow(clippy::mistyped_literal_suffixes)]
 #![allow(clippy::many_single_char_names)]
 
 #[macro_use]
 extern crate static_assertions;
+#[cfg(test)]
+extern crate test;
 
 mod bincode;
 #[macro_use]
 mod util;
+mod anti_cheat;
 mod ball;
 mod bot;
 mod circular_buffer;
+mod config;
+mod directory;
+mod http;
+mod latency;
+mod matchmaking;
+mod outbox;
 mod player;
 mod player_container;
 mod powerup;
 mod protocol;
+mod proxy;
+mod quantize;
+mod rate_limit;
 mod room;
 mod room_manager;
 mod shared_room_data;
+mod snapshot;
+mod stream;
 mod team_data;
+mod tls;
 mod tracker;
+mod validation;
 mod vector;
+mod version;
 
+use crate::config::config;
+use crate::matchmaking::Matchmaker;
 use crate::player::accept_connection;
 use crate::room_manager::RoomManager;
-use futures::lock::Mutex;
+use crate::stream::{ListenAddr, Listener};
+use crate::tls::{reload_on_sighup, Tls};
+use futures::future::join_all;
 use lazy_static::lazy_static;
 use std::env;
-use tokio::net::TcpListener;
-
-type Rooms = Mutex<RoomManager>;
+use std::sync::Arc;
 
 lazy_static! {
-    static ref ROOMS: Rooms = Mutex::new(RoomManager::new());
+    static ref ROOMS: RoomManager = RoomManager::new();
+    static ref MATCHMAKER: Matchmaker = Matchmaker::new();
 }
 
 /// Gets the rooms
 #[inline]
-pub fn rooms() -> &'static Rooms {
+pub fn rooms() -> &'static RoomManager {
     &ROOMS
 }
 
+/// Gets the quick-play matchmaker.
+#[inline]
+pub fn matchmaker() -> &'static Matchmaker {
+    &MATCHMAKER
+}
+
 /// Main entry point.
 #[tokio::main]
 async fn main() {
-    // Setup the websocket server.
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:4242".to_string());
-    let sock = TcpListener::bind(&addr).await.expect("server socket");
-
-    while let Ok((stream, _client_addr)) = sock.accept().await {
-        //debug!("connection from {:?}", _client_addr.ip());
-        tokio::spawn(accept_connection(stream));
+    // Setup the websocket server, every argument is an address to listen on.
+    let mut addrs = env::args()
+        .skip(1)
+        .map(|addr| ListenAddr::parse(&addr))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        addrs.push(ListenAddr::Tcp("127.0.0.1:4242".to_string()));
+    }
+
+    let mut listeners = Vec::new();
+    for addr in &addrs {
+        listeners.push((Listener::bind(addr).await.expect("server socket"), None));
+    }
+
+    // Optional TLS listeners next to the plain ones.
+    let tls_config = &config().tls;
+    if tls_config.is_enabled() {
+        let tls = Arc::new(Tls::new(tls_config).expect("TLS certificate"));
+        for addr in tls_config.listen.split(',') {
+            let listener = Listener::bind(&ListenAddr::parse(addr.trim()))
+                .await
+                .expect("TLS server socket");
+            listeners.push((listener, Some(tls.clone())));
+        }
+        tokio::spawn(reload_on_sighup(tls));
+    }
+
+    join_all(
+        listeners
+            .into_iter()
+            .map(|(listener, tls)| tokio::spawn(listen(listener, tls))),
+    )
+    .await;
+}
+
+/// Accepts connections on a listener.
+async fn listen(listener: Listener, tls: Option<Arc<Tls>>) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        //debug!("connection from {}", peer);
+        tokio::spawn(accept_connection(stream, peer, tls.clone()));
     }
 }
@@ -0,0 +1,218 @@
+use crate::config::{config, env_or, env_secs_or};
+use crate::protocol::MessageToInbox;
+use crate::room::MAX_PLAYERS;
+use crate::rooms;
+use futures::channel::mpsc::Sender;
+use futures::channel::oneshot;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Players a quick-play match is made for, bots included.
+pub const QUICK_PLAY_PLAYERS: u16 = 4;
+
+/// Time the matched players get to join their new room, before it starts without them.
+pub const QUICK_PLAY_JOIN_TIME: Duration = Duration::from_secs(3);
+
+/// Settings for quick play.
+pub struct QuickPlayConfig {
+    /// Time a player waits in the queue before playing with fewer players,
+    /// also the time between matches in a quick-play room.
+    pub wait: Duration,
+    /// Fill up quick-play matches with bots.
+    pub bots: bool,
+}
+
+impl QuickPlayConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            wait: env_secs_or("PINGBONG_QUICK_PLAY_WAIT", 15.0),
+            bots: env_or("PINGBONG_QUICK_PLAY_BOTS", true),
+        }
+    }
+}
+
+/// A player in the queue.
+struct Queued {
+    ticket: u64,
+    matched: oneshot::Sender<Sender<MessageToInbox>>,
+}
+
+/// Groups players that want to play with anyone into quick-play rooms.
+pub struct Matchmaker {
+    queue: Mutex<Vec<Queued>>,
+    next_ticket: AtomicU64,
+}
+
+/// A place in the queue, leaves the queue when dropped.
+pub struct Ticket {
+    id: u64,
+    matchmaker: &'static Matchmaker,
+    /// Gets the inbox of the room the player is matched with.
+    matched: oneshot::Receiver<Sender<MessageToInbox>>,
+    /// When the queue is checked for a smaller match.
+    deadline: Instant,
+    /// Number of players in the queue when the player joined it.
+    pub queued: usize,
+}
+
+/// What happened to a player in the queue.
+pub enum QueueEvent {
+    /// Got a room to join.
+    Matched(Sender<MessageToInbox>),
+    /// The room couldn't be created.
+    Failed,
+    /// Waited long enough, a smaller match may be made.
+    Expired,
+}
+
+impl Matchmaker {
+    /// Creates a matchmaker with an empty queue.
+    pub fn new() -> Self {
+        Self {
+            queue: Mutex::new(Vec::new()),
+            next_ticket: AtomicU64::new(0),
+        }
+    }
+
+    /// Finds a match for a player: a quick-play room with space if there is one,
+    /// otherwise a new room once enough players queued.
+    pub async fn quick_play(&'static self) -> Ticket {
+        let (matched_tx, matched) = oneshot::channel();
+        let mut ticket = Ticket {
+            id: self.next_ticket.fetch_add(1, Ordering::Relaxed),
+            matchmaker: self,
+            matched,
+            deadline: Instant::now() + config().quick_play.wait,
+            queued: 0,
+        };
+
+        if let Some(inbox_tx) = rooms().find_quick_play_room() {
+            let _ = matched_tx.send(inbox_tx);
+            return ticket;
+        }
+
+        let group = {
+            let mut queue = self.queue.lock().expect("queue lock");
+            queue.push(Queued {
+                ticket: ticket.id,
+                matched: matched_tx,
+            });
+            ticket.queued = queue.len();
+            if queue.len() >= QUICK_PLAY_PLAYERS as usize {
+                Some(queue.drain(..).collect())
+            } else {
+                None
+            }
+        };
+        if let Some(group) = group {
+            Self::create_room(group).await;
+        }
+        ticket
+    }
+
+    /// A queued player waited long enough: match everyone in the queue if that's a game.
+    async fn expire(&self, ticket: u64) {
+        let group = {
+            let mut queue = self.queue.lock().expect("queue lock");
+            let enough = config().quick_play.bots || queue.len() > 1;
+            if enough && queue.iter().any(|queued| queued.ticket == ticket) {
+                let count = queue.len().min(MAX_PLAYERS as usize);
+                Some(queue.drain(..count).collect())
+            } else {
+                None
+            }
+        };
+        if let Some(group) = group {
+            Self::create_room(group).await;
+        }
+    }
+
+    /// Leaves the queue.
+    fn leave(&self, ticket: u64) {
+        self.queue
+            .lock()
+            .expect("queue lock")
+            .retain(|queued| queued.ticket != ticket);
+    }
+
+    /// Gets the number of players in the queue.
+    pub fn queued(&self) -> usize {
+        self.queue.lock().expect("queue lock").len()
+    }
+
+    /// Creates a room for a group of queued players.
+    async fn create_room(group: Vec<Queued>) {
+        // The players of a failed room get the closed channel.
+        if let Some((_, inbox_tx)) = rooms().spawn_quick_play().await {
+            for queued in group {
+                let _ = queued.matched.send(inbox_tx.clone());
+            }
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits until the player is matched, or until the queue should be checked again.
+    pub async fn event(&mut self) -> QueueEvent {
+        tokio::select! {
+            matched = &mut self.matched => match matched {
+                Ok(inbox_tx) => QueueEvent::Matched(inbox_tx),
+                Err(_) => QueueEvent::Failed,
+            },
+            _ = tokio::time::sleep_until(self.deadline) => QueueEvent::Expired,
+        }
+    }
+
+    /// Tries a smaller match, the next try is after another wait.
+    pub async fn expire(&mut self) {
+        self.deadline += config().quick_play.wait;
+        self.matchmaker.expire(self.id).await;
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.matchmaker.leave(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queued_players_are_grouped_into_a_room() {
+        let matchmaker = Box::leak(Box::new(Matchmaker::new()));
+
+        let gone = matchmaker.quick_play().await;
+        assert_eq!(gone.queued, 1);
+        drop(gone);
+        let mut tickets = Vec::new();
+        for _ in 1..QUICK_PLAY_PLAYERS {
+            tickets.push(matchmaker.quick_play().await);
+        }
+        assert_eq!(matchmaker.queued(), QUICK_PLAY_PLAYERS as usize - 1);
+
+        tickets.push(matchmaker.quick_play().await);
+        assert_eq!(matchmaker.queued(), 0);
+        let mut inboxes = Vec::new();
+        for ticket in &mut tickets {
+            match ticket.event().await {
+                QueueEvent::Matched(inbox_tx) => inboxes.push(inbox_tx),
+                _ => panic!("not matched"),
+            }
+        }
+        assert!(inboxes.iter().all(|inbox| inbox.same_receiver(&inboxes[0])));
+
+        // The room still has space, the next player goes there without queueing.
+        let mut ticket = matchmaker.quick_play().await;
+        assert_eq!((ticket.queued, matchmaker.queued()), (0, 0));
+        match ticket.event().await {
+            QueueEvent::Matched(inbox_tx) => assert!(inbox_tx.same_receiver(&inboxes[0])),
+            _ => panic!("not matched"),
+        }
+    }
+}
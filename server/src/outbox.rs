@@ -0,0 +1,196 @@
+use crate::config::config;
+use futures::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+/// A queued message.
+#[derive(Debug)]
+struct Outgoing {
+    msg: Message,
+    /// A newer message replaces this one if the client falls behind.
+    droppable: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<Outgoing>,
+    /// Since when the queue has been full.
+    lagging_since: Option<Instant>,
+    /// Send what's queued and close the connection.
+    closing: bool,
+    /// The connection is gone, nothing is sent anymore.
+    closed: bool,
+}
+
+/// Outbound queue of a client, written to the connection by its own task.
+/// Sending never waits on the client.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    state: Mutex<State>,
+    wake_writer: Notify,
+    wake_closed: Notify,
+}
+
+impl Outbox {
+    /// Creates the outbox and spawns the task that writes it to the connection.
+    /// The task ends once the outbox is closed, the owner has to close it before it goes away.
+    pub fn spawn<T: Sink<Message> + Send + Unpin + 'static>(tx: T) -> Arc<Self> {
+        let outbox = Arc::new(Self::default());
+        tokio::spawn(outbox.clone().write_loop(tx));
+        outbox
+    }
+
+    /// Queues a message.
+    /// Droppable messages replace the oldest droppable message once the queue is full.
+    /// Clients that stay behind for too long are disconnected.
+    pub fn push(&self, msg: Message, droppable: bool) {
+        let cfg = &config().rate_limit;
+        let mut state = self.state.lock().expect("outbox");
+        if state.closed || state.closing {
+            return;
+        }
+
+        if state.queue.len() >= cfg.outbox_capacity {
+            let now = Instant::now();
+            let since = *state.lagging_since.get_or_insert(now);
+            // Reliable messages can still grow the queue, but not forever.
+            if now - since > config().timeouts.max_lag
+                || state.queue.len() >= cfg.outbox_capacity * 4
+            {
+                debug!("client lagged out");
+                drop(state);
+                self.close();
+                return;
+            }
+
+            if droppable {
+                if let Some(index) = state.queue.iter().position(|o| o.droppable) {
+                    state.queue.remove(index);
+                }
+            }
+        } else {
+            state.lagging_since = None;
+        }
+
+        state.queue.push_back(Outgoing { msg, droppable });
+        drop(state);
+        self.wake_writer.notify_one();
+    }
+
+    /// Sends what's queued, then closes the connection.
+    pub fn close_gracefully(&self) {
+        self.state.lock().expect("outbox").closing = true;
+        self.wake_writer.notify_one();
+    }
+
+    /// Drops everything and closes the connection.
+    pub fn close(&self) {
+        {
+            let mut state = self.state.lock().expect("outbox");
+            state.closed = true;
+            state.queue.clear();
+        }
+        self.wake_writer.notify_one();
+        self.wake_closed.notify_one();
+    }
+
+    /// Waits until the outbox is closed.
+    pub async fn closed(&self) {
+        loop {
+            if self.state.lock().expect("outbox").closed {
+                return;
+            }
+            self.wake_closed.notified().await;
+        }
+    }
+
+    /// Writes the queued messages to the connection.
+    async fn write_loop<T: Sink<Message> + Unpin>(self: Arc<Self>, mut tx: T) {
+        // A client that doesn't read at all would block a write forever.
+        let write_timeout = config().timeouts.max_lag;
+        loop {
+            let (next, closing) = {
+                let mut state = self.state.lock().expect("outbox");
+                if state.closed {
+                    break;
+                }
+                (state.queue.pop_front(), state.closing)
+            };
+
+            match next {
+                Some(outgoing) => match timeout(write_timeout, tx.send(outgoing.msg)).await {
+                    Ok(Ok(())) => {}
+                    _ => break,
+                },
+                None if closing => {
+                    let _ = timeout(write_timeout, tx.close()).await;
+                    break;
+                }
+                None => self.wake_writer.notified().await,
+            }
+        }
+
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::Client;
+    use crate::protocol::MessageToClient;
+    use crate::stream::PeerAddr;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn text(outbox: &Outbox, index: usize) -> String {
+        let state = outbox.state.lock().unwrap();
+        state.queue[index].msg.to_text().unwrap().to_owned()
+    }
+
+    #[test]
+    fn full_outbox_drops_stale_syncs() {
+        let capacity = config().rate_limit.outbox_capacity;
+        let outbox = Outbox::default();
+        outbox.push(Message::text("start"), false);
+        for i in 1..capacity {
+            outbox.push(Message::text(format!("sync {}", i)), true);
+        }
+
+        // The oldest sync makes room, reliable messages are always queued.
+        outbox.push(Message::text("newest sync"), true);
+        outbox.push(Message::text("leave"), false);
+        assert_eq!(outbox.state.lock().unwrap().queue.len(), capacity + 1);
+        assert_eq!(text(&outbox, 0), "start");
+        assert_eq!(text(&outbox, 1), "sync 2");
+        assert_eq!(text(&outbox, capacity - 1), "newest sync");
+        assert_eq!(text(&outbox, capacity), "leave");
+
+        // Lagging for too long closes the connection.
+        outbox.state.lock().unwrap().lagging_since =
+            Some(Instant::now() - config().timeouts.max_lag * 2);
+        outbox.push(Message::text("too late"), true);
+        let state = outbox.state.lock().unwrap();
+        assert!(state.closed && state.queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropping_a_client_ends_the_writer() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut client = Client::new(tx, PeerAddr::Unix);
+        let outbox = client.outbox();
+        client.send(&MessageToClient::Ack).await;
+        drop(client);
+
+        // The queued message still goes out, then the connection closes.
+        timeout(Duration::from_secs(1), outbox.closed())
+            .await
+            .expect("writer still running");
+        assert!(matches!(rx.next().await, Some(Message::Binary(_))));
+        assert!(rx.next().await.is_none());
+    }
+}
@@ -1,26 +1,43 @@
-use crate::ball::{BALL_RADIUS, BALL_RADIUS_ANGLE, NO_TEAM, SPIN_MAX};
+use crate::anti_cheat::MoveStats;
+use crate::ball::{BALL_RADIUS, BALL_RADIUS_ANGLE, MAX_CLIENT_SPIN, NO_TEAM};
+use crate::config::config;
+use crate::http;
+use crate::latency::{default_rewind_window, LatencyTracker, PingToken};
+use crate::matchmaking::{QueueEvent, Ticket};
+use crate::outbox::Outbox;
 use crate::protocol::{
     ClientMoveUpdate, ClientSync, JoinData, LobbyMessageFromClient, LoginMessageFromClient,
-    MessageToClient, MessageToInbox, OutdatedReason, RoomMessageFromClient,
+    MessageToClient, MessageToInbox, RoomMessageFromClient, RoomPage, RoomQuery,
 };
+use crate::proxy::read_proxy_header;
+use crate::rate_limit::{ConnectionGuard, MessageCategory, RateLimits};
 use crate::room::{CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH};
-use crate::room_manager::RoomSpawnFailReason;
-use crate::rooms;
+use crate::room_manager::{
+    RoomLocation, RoomSpawnFailReason, RoomSubscription, LIST_CACHE_TTL, MAX_LISTED_ROOMS,
+};
+use crate::stream::{PeerAddr, ServerStream};
 use crate::team_data::TeamData;
+use crate::tls::Tls;
 use crate::util::clampf32;
+use crate::validation::{validate_seq_nr, Validate};
 use crate::vector::Vector;
+use crate::version::{with_version, EncodeCache, Features, ProtocolVersion};
+use crate::{matchmaker, rooms};
 use bytes::Bytes;
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::mpsc::Sender;
 use futures::channel::oneshot;
-use futures::stream::{SplitSink, SplitStream};
-use futures::{SinkExt, StreamExt};
+use futures::future::{pending, poll_fn};
+use futures::stream::SplitStream;
+use futures::{Sink, SinkExt, StreamExt};
 use serde::Deserialize;
-use std::net::IpAddr;
 use std::num::NonZeroU8;
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{interval, timeout, timeout_at, Instant};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::{Error, Message};
-use tokio_tungstenite::{accept_async_with_config, WebSocketStream};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
 
 pub type ClientId = u32;
 
@@ -28,9 +45,6 @@ pub type ClientId = u32;
 /// Assuming a message is sent every 16ms at max, we run out at about 4400 minutes.
 pub type SeqNr = u32;
 
-/// Protocol version.
-const PROTOCOL_VERSION: u32 = 9;
-
 /// Maximum possible moves per server tick.
 const MAX_MOVE_PER_SERVER_TICK: u8 = 2;
 
@@ -41,8 +55,10 @@ const MAX_MOVE_QUEUE: u8 = MAX_MOVE_PER_SERVER_TICK + (1 + 1);
 
 const PLAYER_W_PADDING: f32 = 4.0;
 
-type TxChannel = SplitSink<WebSocketStream<TcpStream>, Message>;
-type RxChannel = SplitStream<WebSocketStream<TcpStream>>;
+/// Relative margin before a move counts as clamped, for FP differences with the client.
+const CLAMP_TOLERANCE: f32 = 1.0001;
+
+type RxChannel = SplitStream<WebSocketStream<ServerStream>>;
 
 enum PacketResult<T> {
     Ok(T),
@@ -52,8 +68,10 @@ enum PacketResult<T> {
 
 #[derive(Debug)]
 pub struct Client {
-    tx: TxChannel,
-    ip: IpAddr,
+    outbox: Arc<Outbox>,
+    addr: PeerAddr,
+    version: ProtocolVersion,
+    latency: LatencyTracker,
 }
 
 #[derive(Copy, Clone)]
@@ -93,6 +111,9 @@ pub struct Player {
     team_nr: u8,
     bounds: PlayerBB,
     name: String,
+    stats: MoveStats,
+    /// How far back in time hits are accepted.
+    rewind_window: Duration,
 }
 
 #[derive(Copy, Clone)]
@@ -129,6 +150,8 @@ impl Player {
             name,
             team_nr,
             bounds: Default::default(),
+            stats: Default::default(),
+            rewind_window: default_rewind_window(),
         }
     }
 
@@ -138,6 +161,7 @@ impl Player {
         self.move_count = 0;
         self.spin = 0.0;
         self.ball_hit = None;
+        self.stats = Default::default();
     }
 
     #[inline]
@@ -155,6 +179,21 @@ impl Player {
         &self.name
     }
 
+    #[inline]
+    pub fn stats_mut(&mut self) -> &mut MoveStats {
+        &mut self.stats
+    }
+
+    #[inline]
+    pub fn rewind_window(&self) -> Duration {
+        self.rewind_window
+    }
+
+    #[inline]
+    pub fn set_rewind_window(&mut self, rewind_window: Duration) {
+        self.rewind_window = rewind_window;
+    }
+
     #[inline]
     pub fn team_nr(&self) -> u8 {
         self.team_nr
@@ -282,23 +321,33 @@ impl Player {
             return;
         }
 
-        if update.seq_nr <= self.move_seq_nr {
-            debug!(
-                "drop out of order move {} {}",
-                update.seq_nr, self.move_seq_nr
-            );
+        if let Err(e) = validate_seq_nr(update.seq_nr, self.move_seq_nr) {
+            debug!("drop out of order move {:?}", e);
             return;
         }
 
+        let (delta, spin) = if team_data.inverted_controls() {
+            (-update.delta, -update.spin)
+        } else {
+            (update.delta, update.spin)
+        };
+
+        let max_move = team_data.max_move_factor() * CLAMP_TOLERANCE;
+        self.stats.record_move(
+            !delta.is_finite() || delta.abs() > max_move,
+            update.seq_nr - self.move_seq_nr,
+            update.spin,
+            &config().anti_cheat,
+        );
+
         // Clamp instead of rejecting because we don't know what could happen with FP issues.
         let delta = clampf32(
-            update.delta,
+            delta,
             -team_data.max_move_factor(),
             team_data.max_move_factor(),
         );
         self.pos = clampf32(self.pos + delta, self.min_pos, self.max_pos);
-        // *2.0 because it can change the spin completely to the other direction.
-        self.spin = clampf32(update.spin, -SPIN_MAX * 2.0, SPIN_MAX * 2.0);
+        self.spin = clampf32(spin, -MAX_CLIENT_SPIN, MAX_CLIENT_SPIN);
 
         self.move_seq_nr = update.seq_nr;
         if update.ball_hit != NO_TEAM {
@@ -356,27 +405,100 @@ impl Player {
 }
 
 impl Client {
-    /// Creates a new Client.
-    pub fn new(tx: TxChannel, ip: IpAddr) -> Self {
-        Self { tx, ip }
+    /// Creates a new Client that sends to `tx`, the websocket outside of tests.
+    pub fn new<T: Sink<Message> + Send + Unpin + 'static>(tx: T, addr: PeerAddr) -> Self {
+        Self {
+            outbox: Outbox::spawn(tx),
+            addr,
+            version: ProtocolVersion::CURRENT,
+            latency: Default::default(),
+        }
     }
 
-    /// Gets the IP.
+    /// Gets the address.
     #[inline]
-    pub fn ip(&self) -> IpAddr {
-        self.ip
+    pub fn addr(&self) -> PeerAddr {
+        self.addr
     }
 
-    /// Send a message to the player.
+    /// Gets the negotiated protocol version.
+    #[inline]
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Send a message to the player, in the player's protocol version.
     pub async fn send(&mut self, msg: &MessageToClient<'_>) {
-        self.send_bytes(crate::bincode::serialize(&msg).expect("encode").into())
-            .await
+        if let Some(bytes) = self.version.encode(msg) {
+            self.send_bytes(bytes).await
+        }
+    }
+
+    /// Send a message that is shared between players, encoding it at most once per version.
+    pub async fn send_cached(&mut self, cache: &mut EncodeCache, msg: &MessageToClient<'_>) {
+        if let Some(bytes) = cache.get(self.version, msg) {
+            self.send_bytes(bytes).await
+        }
+    }
+
+    /// Like `send_cached`, but the message may be skipped if the player falls behind.
+    /// Only for state that a later message fully replaces.
+    pub fn send_droppable(&mut self, cache: &mut EncodeCache, msg: &MessageToClient<'_>) {
+        if let Some(bytes) = cache.get(self.version, msg) {
+            self.outbox.push(Message::binary(bytes), true);
+        }
+    }
+
+    /// Gets the outbox, to notice when the connection is closed.
+    #[inline]
+    pub fn outbox(&self) -> Arc<Outbox> {
+        self.outbox.clone()
+    }
+
+    /// Kicks the player by closing the connection.
+    /// The player connection loop will take care of leaving the room.
+    pub async fn kick(&mut self) {
+        self.outbox.close_gracefully();
+    }
+
+    /// Pings the player, the pong keeps the connection alive.
+    /// Clients that support it get a ping that measures the round trip time.
+    pub async fn ping(&mut self) {
+        if self.version.supports(Features::LATENCY) {
+            let token = self.latency.ping(Instant::now());
+            self.send(&MessageToClient::Ping(token)).await;
+        } else {
+            self.outbox.push(Message::Ping(Vec::new()), false);
+        }
+    }
+
+    /// Records a pong that arrived at `at`. Returns false if it doesn't answer the last ping.
+    pub fn pong(&mut self, token: PingToken, at: Instant) -> bool {
+        self.latency.pong(token, at)
+    }
+
+    /// Gets the round trip time, if it was measured.
+    #[inline]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.latency.rtt()
+    }
+
+    /// Gets how far back in time hits of the player are accepted.
+    #[inline]
+    pub fn rewind_window(&self) -> Duration {
+        self.latency.rewind_window()
     }
 
     /// Send bytes to the player.
     pub async fn send_bytes(&mut self, msg: Bytes) {
-        let msg = Message::binary(msg);
-        let _ = self.tx.send(msg).await;
+        self.outbox.push(Message::binary(msg), false);
+    }
+}
+
+impl Drop for Client {
+    /// Sends what's left and closes the connection, the writer task ends with it.
+    fn drop(&mut self) {
+        self.outbox.close_gracefully();
     }
 }
 
@@ -396,35 +518,114 @@ fn deserialize_msg<'de, T: Deserialize<'de>>(
 }
 
 /// Accept client connection.
+/// The TLS acceptor is given for connections on the TLS listener.
 pub async fn accept_connection(
-    stream: TcpStream,
+    mut stream: ServerStream,
+    peer: PeerAddr,
+    tls: Option<Arc<Tls>>,
 ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
-    // Disable Nagle's algorithm.
-    let _ = stream.set_nodelay(true);
     let ws_cfg = WebSocketConfig {
         max_message_size: Some(8192),
         max_frame_size: Some(8192),
         ..Default::default()
     };
-    let ip = stream.peer_addr().expect("peer address should exist").ip();
-    let ws_stream = accept_async_with_config(stream, Some(ws_cfg)).await?;
+    let timeouts = &config().timeouts;
+    let proxy = &config().proxy;
+    let mut addr = peer;
+
+    // Behind a proxy we only know who we're talking to after the handshake.
+    let mut connection_guard = None;
+    if !proxy.is_trusted(peer) {
+        connection_guard = ConnectionGuard::acquire(addr);
+        if connection_guard.is_none() {
+            debug!("too many connections from {}", addr);
+            return Ok(());
+        }
+    }
+
+    if proxy.proxy_protocol {
+        match timeout(timeouts.handshake, read_proxy_header(&mut stream)).await {
+            Ok(Ok(Some(source))) if proxy.is_trusted(peer) => addr = PeerAddr::Ip(source),
+            Ok(Ok(_)) => {}
+            _ => return Ok(()),
+        }
+    }
+
+    let stream = match tls {
+        Some(tls) => match timeout(timeouts.handshake, tls.acceptor().accept(stream)).await {
+            Ok(Ok(stream)) => ServerStream::Tls(Box::new(stream)),
+            _ => return Ok(()),
+        },
+        None => stream,
+    };
+
+    // Plain HTTP requests are answered here, only websocket upgrades continue.
+    let stream = if config().http.enabled {
+        match timeout(timeouts.handshake, http::route(stream, &config().http)).await {
+            Ok(Ok(Some(stream))) => stream,
+            _ => return Ok(()),
+        }
+    } else {
+        stream
+    };
+
+    let mut forwarded_ip = None;
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        forwarded_ip = request
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| proxy.forwarded_for(addr, header));
+        Ok(response)
+    };
+    let ws_stream = match timeout(
+        timeouts.handshake,
+        accept_hdr_async_with_config(stream, callback, Some(ws_cfg)),
+    )
+    .await
+    {
+        Ok(ws_stream) => ws_stream?,
+        Err(_) => return Ok(()),
+    };
+    let addr = forwarded_ip.map_or(addr, PeerAddr::Ip);
+    // A Unix socket peer is a proxy, without the client address all its clients would share
+    // the per-address limits.
+    if addr == PeerAddr::Unix {
+        debug!("no client address from a Unix socket peer");
+        return Ok(());
+    }
+    let _connection_guard = match connection_guard.or_else(|| ConnectionGuard::acquire(addr)) {
+        Some(guard) => guard,
+        None => {
+            debug!("too many connections from {}", addr);
+            return Ok(());
+        }
+    };
     let (tx, mut rx) = ws_stream.split();
     let name: String;
-    let mut client = Client::new(tx, ip);
+    let mut client = Client::new(tx, addr);
+    let mut limits = RateLimits::new();
 
     // Name selection & sanitization.
+    let name_deadline = Instant::now() + timeouts.name_selection;
     loop {
-        match deserialize_msg::<LoginMessageFromClient>(rx.next().await.as_ref()) {
+        let msg = match timeout_at(name_deadline, rx.next()).await {
+            Ok(msg) => msg,
+            Err(_) => return Ok(()),
+        };
+
+        match deserialize_msg::<LoginMessageFromClient>(msg.as_ref()) {
+            PacketResult::Ok(_) if !limits.allow(MessageCategory::Lobby) => {
+                client.send(&MessageToClient::NameError).await;
+            }
             PacketResult::Ok(LoginMessageFromClient::SetName(version, set_name)) => {
-                if version != PROTOCOL_VERSION {
-                    client
-                        .send(&MessageToClient::Outdated(if version < PROTOCOL_VERSION {
-                            OutdatedReason::Client
-                        } else {
-                            OutdatedReason::Server
-                        }))
-                        .await;
-                    return Ok(());
+                match ProtocolVersion::negotiate(version) {
+                    Ok(version) => client.version = version,
+                    Err(reason) => {
+                        client.send(&MessageToClient::Outdated(reason)).await;
+                        return Ok(());
+                    }
                 }
 
                 if set_name.is_empty()
@@ -444,12 +645,74 @@ pub async fn accept_connection(
         }
     }
 
+    let version = client.version();
+    debug!("{} speaks protocol version {}", addr, version.get());
+    if version.supports(Features::NEGOTIATION) {
+        client
+            .send(&MessageToClient::Negotiated(version, version.features()))
+            .await;
+    }
     client.send(&MessageToClient::Ack).await;
 
+    // Room list updates and the queue don't count as activity, only messages from the client do.
+    let mut subscription = None;
+    let mut queue: Option<Ticket> = None;
+    let mut list_updates = interval(LIST_CACHE_TTL);
+    let mut idle_deadline = Instant::now() + timeouts.lobby_idle;
     loop {
-        match deserialize_msg::<LobbyMessageFromClient>(rx.next().await.as_ref()) {
+        let msg = tokio::select! {
+            msg = timeout_at(idle_deadline, rx.next()) => match msg {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+            _ = list_updates.tick(), if subscription.is_some() => {
+                send_room_list_update(&mut client, subscription.as_mut().expect("subscription")).await;
+                continue;
+            }
+            event = queue_event(&mut queue) => {
+                match event {
+                    QueueEvent::Matched(inbox_tx) => {
+                        queue = None;
+                        subscription = None;
+                        match player_connected(client, &mut rx, &mut limits, inbox_tx, &name).await {
+                            Some(c) => client = c,
+                            None => break,
+                        }
+                    }
+                    QueueEvent::Failed => {
+                        queue = None;
+                        client.send(&MessageToClient::JoinRoomError).await;
+                    }
+                    QueueEvent::Expired => {
+                        if let Some(ticket) = &mut queue {
+                            ticket.expire().await;
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+        idle_deadline = Instant::now() + timeouts.lobby_idle;
+
+        match deserialize_msg::<LobbyMessageFromClient>(msg.as_ref()) {
+            PacketResult::Ok(msg) if msg.category().is_some_and(|c| !limits.allow(c)) => {
+                if let Some(reply) = msg.rate_limited_reply() {
+                    client.send(&reply).await;
+                }
+            }
             PacketResult::Ok(msg) => {
-                client = lobby_message(client, &mut rx, msg, &name).await;
+                let lobby = Lobby {
+                    rx: &mut rx,
+                    limits: &mut limits,
+                    subscription: &mut subscription,
+                    queue: &mut queue,
+                    name: &name,
+                };
+                match lobby_message(client, lobby, msg).await {
+                    Some(c) => client = c,
+                    // The client was lost in a crashed room.
+                    None => break,
+                }
             }
             PacketResult::Ignore => {}
             PacketResult::Err => break,
@@ -459,77 +722,203 @@ pub async fn accept_connection(
     Ok(())
 }
 
+/// State of a client in the lobby.
+struct Lobby<'a> {
+    rx: &'a mut RxChannel,
+    limits: &'a mut RateLimits,
+    /// The page of the room list the client gets updates for.
+    subscription: &'a mut Option<RoomSubscription>,
+    /// The place in the quick-play queue.
+    queue: &'a mut Option<Ticket>,
+    name: &'a str,
+}
+
 /// Lobby message handler.
 async fn lobby_message(
     mut client: Client,
-    rx: &mut RxChannel,
+    lobby: Lobby<'_>,
     msg: LobbyMessageFromClient,
-    name: &str,
-) -> Client {
+) -> Option<Client> {
     let rooms = rooms();
+    let Lobby {
+        rx,
+        limits,
+        subscription,
+        queue,
+        name,
+    } = lobby;
 
     match msg {
         LobbyMessageFromClient::CreateRoom => {
-            let result = rooms.lock().await.spawn(client.ip(), name.to_owned());
+            let result = rooms.spawn(client.addr(), name.to_owned()).await;
             match result {
                 Ok((room_id, inbox_tx)) => {
                     client.send(&MessageToClient::CreatedRoom(room_id)).await;
-                    client = player_connected(client, rx, inbox_tx, name).await;
+                    *subscription = None;
+                    *queue = None;
+                    return player_connected(client, rx, limits, inbox_tx, name).await;
                 }
 
                 Err(RoomSpawnFailReason::TooManyFromSameIp) => {
                     client.send(&MessageToClient::TooManyRooms).await;
                 }
+
+                Err(RoomSpawnFailReason::Directory) => {
+                    client.send(&MessageToClient::JoinRoomError).await;
+                }
             }
         }
 
-        LobbyMessageFromClient::JoinRoom(room_id) => {
-            let room = rooms.lock().await.get_tx(room_id.as_str());
-            if let Some(inbox_tx) = room {
-                client = player_connected(client, rx, inbox_tx, name).await;
-            } else {
+        LobbyMessageFromClient::JoinRoom(room_id) => match rooms.locate(room_id.as_str()).await {
+            RoomLocation::Local(inbox_tx) => {
+                *subscription = None;
+                *queue = None;
+                return player_connected(client, rx, limits, inbox_tx, name).await;
+            }
+            RoomLocation::Remote(node) if client.version().supports(Features::REDIRECT) => {
+                client.send(&MessageToClient::Redirect(&node)).await;
+            }
+            RoomLocation::Remote(_) | RoomLocation::Missing => {
                 client.send(&MessageToClient::JoinRoomError).await;
             }
-        }
+        },
 
         LobbyMessageFromClient::ListRooms => {
-            let rooms = rooms.lock().await;
-            let list = rooms.list().await;
+            let list = rooms.list();
             let playing_count = rooms.playing_rooms();
-            drop(rooms);
-            let list_slice = list.as_slice();
+            let list_slice = &list.rooms[..list.rooms.len().min(MAX_LISTED_ROOMS)];
             client
                 .send(&MessageToClient::ListRooms(playing_count, list_slice))
                 .await;
         }
+
+        LobbyMessageFromClient::QueryRooms(query) => {
+            send_room_page(&mut client, &query).await;
+        }
+
+        LobbyMessageFromClient::SubscribeRooms(query) => {
+            *subscription = Some(send_room_page(&mut client, &query).await);
+        }
+
+        LobbyMessageFromClient::UnsubscribeRooms => *subscription = None,
+
+        LobbyMessageFromClient::QuickPlay => {
+            if queue.is_none() && client.version().supports(Features::QUICK_PLAY) {
+                let ticket = matchmaker().quick_play().await;
+                client
+                    .send(&MessageToClient::Queued(ticket.queued as u16))
+                    .await;
+                *queue = Some(ticket);
+            }
+        }
+
+        LobbyMessageFromClient::LeaveQueue => *queue = None,
     }
 
+    Some(client)
+}
+
+/// Waits for something to happen to the player in the queue, forever if not queued.
+async fn queue_event(queue: &mut Option<Ticket>) -> QueueEvent {
+    match queue {
+        Some(ticket) => ticket.event().await,
+        None => pending().await,
+    }
+}
+
+/// Sends a page of the room list, returns a subscription to it.
+async fn send_room_page(client: &mut Client, query: &RoomQuery) -> RoomSubscription {
+    let rooms = rooms();
+    let list = rooms.list();
+    let playing = rooms.playing_rooms();
+    let (matching, page) = list.query(query);
     client
+        .send(&MessageToClient::RoomPage(RoomPage {
+            page: query.page,
+            matching,
+            playing,
+            rooms: &page,
+        }))
+        .await;
+    RoomSubscription::new(query.clone(), &page, matching, playing)
+}
+
+/// Sends the changes to a subscribed page of the room list.
+async fn send_room_list_update(client: &mut Client, subscription: &mut RoomSubscription) {
+    let rooms = rooms();
+    let list = rooms.list();
+    if let Some(update) = subscription.update(&list, rooms.playing_rooms()) {
+        client.send(&MessageToClient::RoomListUpdate(update)).await;
+    }
 }
 
 /// Player connected async loop.
+/// Returns the client unless it got lost in a crashed room.
 pub async fn player_connected(
     client: Client,
     rx: &mut RxChannel,
-    mut inbox_tx: UnboundedSender<MessageToInbox>,
+    limits: &mut RateLimits,
+    mut inbox_tx: Sender<MessageToInbox>,
     name: &str,
-) -> Client {
+) -> Option<Client> {
+    let outbox = client.outbox();
+    let version = client.version();
+
     // Setup a one-shot channel for communicating the join message to the inbox.
-    let (join_data, mut inbox): (JoinData, UnboundedSender<MessageToInbox>) = {
+    let (mut join_data, mut inbox): (JoinData, Sender<MessageToInbox>) = {
         let (join_tx, join_rx) = oneshot::channel();
-
-        inbox_tx
-            .send(MessageToInbox::JoinPlayer(join_tx, client, name.to_owned()))
-            .await
-            .expect("connect player");
+        let msg = MessageToInbox::JoinPlayer(join_tx, client, name.to_owned());
+
+        // Wait for space in the inbox. The room may have crashed in the meantime, which gives
+        // us the client back.
+        let result = match poll_fn(|cx| inbox_tx.poll_ready(cx)).await {
+            Ok(()) => inbox_tx.try_send(msg).map_err(|e| e.into_inner()),
+            Err(_) => Err(msg),
+        };
+        if let Err(msg) = result {
+            if let MessageToInbox::JoinPlayer(_, mut client, _) = msg {
+                client.send(&MessageToClient::JoinRoomError).await;
+                return Some(client);
+            }
+            unreachable!();
+        }
 
         // Continue when the join is confirmed.
-        (join_rx.await.expect("join data"), inbox_tx)
+        match join_rx.await.ok()? {
+            Ok(join_data) => (join_data, inbox_tx),
+            Err(mut client) => {
+                client.send(&MessageToClient::JoinRoomError).await;
+                return Some(client);
+            }
+        }
     };
 
     // Message handling loop.
+    // The room pings the clients, so a silent connection means the player is gone.
+    let ping_timeout = config().timeouts.ping_timeout;
     loop {
-        match deserialize_msg::<RoomMessageFromClient>(rx.next().await.as_ref()) {
+        let msg = tokio::select! {
+            msg = timeout(ping_timeout, rx.next()) => match msg {
+                Ok(msg) => msg,
+                Err(_) => {
+                    debug!("ping timeout {}", join_data.id);
+                    break;
+                }
+            },
+            client = &mut join_data.closed => return client.ok(),
+            _ = outbox.closed() => {
+                debug!("connection closed {}", join_data.id);
+                break;
+            }
+        };
+
+        match with_version(version, || {
+            deserialize_msg::<RoomMessageFromClient>(msg.as_ref())
+        }) {
+            PacketResult::Ok(msg) if msg.validate().is_err() => {
+                debug!("drop invalid message from {}", join_data.id);
+            }
+            PacketResult::Ok(msg) if msg.category().is_some_and(|c| !limits.allow(c)) => {}
             PacketResult::Ok(msg) => match msg {
                 RoomMessageFromClient::Move(msg) => {
                     let _ = inbox
@@ -558,6 +947,36 @@ pub async fn player_connected(
                 RoomMessageFromClient::RemoveBot => {
                     let _ = inbox.send(MessageToInbox::RemoveBot(join_data.id)).await;
                 }
+
+                RoomMessageFromClient::Pong(token) => {
+                    let _ = inbox
+                        .send(MessageToInbox::Pong(join_data.id, token, Instant::now()))
+                        .await;
+                }
+
+                RoomMessageFromClient::ClockSync(client_time) => {
+                    let _ = inbox
+                        .send(MessageToInbox::ClockSync(
+                            join_data.id,
+                            client_time,
+                            Instant::now(),
+                        ))
+                        .await;
+                }
+
+                RoomMessageFromClient::AckSync(frame) => {
+                    let _ = inbox
+                        .send(MessageToInbox::AckSync(join_data.id, frame))
+                        .await;
+                }
+
+                RoomMessageFromClient::Pause => {
+                    let _ = inbox.send(MessageToInbox::Pause(join_data.id)).await;
+                }
+
+                RoomMessageFromClient::Resume => {
+                    let _ = inbox.send(MessageToInbox::Resume(join_data.id)).await;
+                }
             },
             PacketResult::Ignore => {}
             PacketResult::Err => break,
@@ -566,9 +985,58 @@ pub async fn player_connected(
 
     // Handle player leave.
     let (leave_tx, leave_rx) = oneshot::channel();
-    inbox
+    if inbox
         .send(MessageToInbox::RemovePlayer(leave_tx, join_data.id))
         .await
-        .expect("leave");
-    leave_rx.await.expect("client")
+        .is_ok()
+    {
+        if let Ok(client) = leave_rx.await {
+            return Some(client);
+        }
+    }
+
+    // The room crashed before handling the leave.
+    join_data.closed.await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::client_async;
+
+    #[tokio::test]
+    async fn throttled_room_lists_keep_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            accept_connection(ServerStream::Tcp(stream), PeerAddr::Ip(peer.ip()), None).await
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut ws, _) = client_async(format!("ws://{}/", addr), stream)
+            .await
+            .unwrap();
+
+        // SetName, then more ListRooms than the lobby burst allows.
+        let set_name = crate::bincode::serialize(&(0u32, ProtocolVersion::CURRENT.get(), "a"));
+        ws.send(Message::Binary(set_name.unwrap())).await.unwrap();
+        let list_rooms = crate::bincode::serialize(&2u32).unwrap();
+        let requests = config().rate_limit.lobby_burst as usize * 2;
+        for _ in 0..requests {
+            ws.send(Message::Binary(list_rooms.clone())).await.unwrap();
+        }
+        ws.send(Message::Ping(Vec::new())).await.unwrap();
+
+        // Negotiated and Ack, then a list for each request that wasn't throttled.
+        let mut replies = 0;
+        loop {
+            match ws.next().await {
+                Some(Ok(Message::Binary(_))) => replies += 1,
+                Some(Ok(Message::Pong(_))) => break,
+                msg => panic!("connection lost: {:?}", msg),
+            }
+        }
+        assert!(replies < requests + 2);
+    }
 }
@@ -1,14 +1,26 @@
+use crate::anti_cheat::{AntiCheatConfig, Violation};
 use crate::ball::{Ball, BallTickResult, RoomDataForBall, MOVEMENT_BUFFER_CAP};
 use crate::bot::{BallMask, Bot};
 use crate::player::{ClientId, Player, SeqNr};
 use crate::protocol::{BallSync, ClientMoveUpdate, ClientSync};
-use crate::room::{MAX_TEAMS, TPF};
-use crate::shared_room_data::SharedRoomData;
-use crate::team_data::TeamData;
+use crate::room::{TickRate, MAX_TEAMS};
+use crate::team_data::{TeamData, TeamMask, TeamModifierKind};
 use fnv::FnvHashMap;
 use smallvec::SmallVec;
 use std::cell::RefCell;
-use std::collections::hash_map::{IterMut, Values, ValuesMut};
+use std::collections::hash_map::{Iter, IterMut, Values, ValuesMut};
+use std::time::Duration;
+
+/// Result of checking a hit claimed by a client.
+#[derive(Eq, PartialEq)]
+enum ClaimedHit {
+    /// The hit was missed by the server, the ball got rewound.
+    Rewound,
+    /// The server already saw the hit.
+    AlreadyAcked,
+    /// The hit could not be confirmed.
+    Rejected,
+}
 
 pub struct PlayerContainer {
     container: FnvHashMap<ClientId, RefCell<Player>>,
@@ -19,7 +31,7 @@ impl PlayerContainer {
     pub fn new() -> Self {
         Self {
             container: Default::default(),
-            team_data: [Default::default(); MAX_TEAMS as usize],
+            team_data: Default::default(),
         }
     }
 
@@ -51,24 +63,65 @@ impl PlayerContainer {
         self.container.values_mut()
     }
 
+    /// Iterator.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, u32, RefCell<Player>> {
+        self.container.iter()
+    }
+
     /// Values mut iterator.
     #[inline]
     pub fn iter_mut(&mut self) -> IterMut<'_, u32, RefCell<Player>> {
         self.container.iter_mut()
     }
 
-    /// Sets the speed of a team.
-    pub fn set_team_speed(&mut self, team_nr: u8, speed: f32) {
+    /// Gets the team data of a team.
+    #[inline]
+    pub fn team_data(&self, team_nr: u8) -> &TeamData {
+        &self.team_data[team_nr as usize]
+    }
+
+    /// Adds a modifier to every team in the mask.
+    pub fn add_team_modifier(&mut self, teams: TeamMask, kind: TeamModifierKind, duration: f32) {
         for (_, td) in self
             .team_data
             .iter_mut()
             .enumerate()
-            .filter(|(i, _)| *i != team_nr as usize)
+            .filter(|(i, _)| teams & (1 << i) != 0)
         {
-            td.set_speed(speed);
+            td.add_modifier(kind, duration);
         }
     }
 
+    /// Ticks the team modifiers.
+    pub fn tick_team_data(&mut self, delta: f32) {
+        for td in self.team_data.iter_mut() {
+            td.tick(delta);
+        }
+    }
+
+    /// Resets the team data, e.g. for a new match.
+    pub fn reset_team_data(&mut self) {
+        self.team_data = Default::default();
+    }
+
+    /// Checks the move statistics of the players, returns the newly flagged players.
+    pub fn check_anti_cheat(
+        &mut self,
+        config: &AntiCheatConfig,
+    ) -> SmallVec<[(ClientId, Violation); 1]> {
+        self.container
+            .iter_mut()
+            .filter_map(|(&id, player)| {
+                player
+                    .get_mut()
+                    .stats_mut()
+                    .check(config)
+                    .map(|violation| (id, violation))
+            })
+            .collect()
+    }
+
     /// Queues a move for a player.
     pub fn queue_move_for(&mut self, id: ClientId, update: ClientMoveUpdate) {
         if let Some(player) = self.container.get_mut(&id) {
@@ -77,8 +130,15 @@ impl PlayerContainer {
         }
     }
 
+    /// Sets how far back in time hits of a player are accepted.
+    pub fn set_rewind_window(&mut self, id: ClientId, rewind_window: Duration) {
+        if let Some(player) = self.container.get_mut(&id) {
+            player.get_mut().set_rewind_window(rewind_window);
+        }
+    }
+
     /// Ticks the bot players.
-    pub fn tick_bots(&mut self, bots: &[Bot], balls: &[Ball]) {
+    pub fn tick_bots(&mut self, bots: &[Bot], balls: &[Ball], tick_rate: TickRate) {
         // We need to keep track of the ball masks of individual teams as to not conflict the decisions.
         let mut ball_masks = [BallMask::new(); MAX_TEAMS];
 
@@ -94,6 +154,7 @@ impl PlayerContainer {
                 balls,
                 //&self.team_data[bot_player.team_nr() as usize],
                 ball_masks[team_nr],
+                tick_rate,
             );
             drop(bot_player);
             self.queue_move_for(bot.id(), bot_tick_result.move_update);
@@ -102,12 +163,15 @@ impl PlayerContainer {
     }
 
     /// Handles late collisions.
+    /// Hits are checked against the ball history, as far back as the rewind window of the player.
     pub fn handle_late_collisions(
         &mut self,
         frame_time: SeqNr,
         balls: &mut [Ball],
-        shared_data: &SharedRoomData,
+        room_data_for_ball: RoomDataForBall,
+        tick_rate: TickRate,
     ) -> (Vec<ClientSync>, SmallVec<[BallSync; 3]>) {
+        let history_len = tick_rate.history_len();
         let mut client_syncs = Vec::new();
         let mut ball_syncs = SmallVec::new();
 
@@ -117,9 +181,11 @@ impl PlayerContainer {
                 client_syncs.push(sync);
             }
 
-            if let Some(ball_hit) = player.ball_hit() {
-                let ball = &mut balls[ball_hit.id() as usize];
-
+            // The ball can be gone already, e.g. when a multiball ended.
+            let claimed_hit = if let Some((ball_hit, ball)) = player
+                .ball_hit()
+                .and_then(|ball_hit| Some((ball_hit, balls.get_mut(ball_hit.id() as usize)?)))
+            {
                 // Necessary because we want a regular borrow to process the ball.
                 drop(player);
                 let player = player_refcell.borrow();
@@ -127,33 +193,25 @@ impl PlayerContainer {
                 let mut collides = || {
                     debug!("{} {}", frame_time, player.move_seq_nr());
 
-                    let offset = match frame_time.checked_sub(player.move_seq_nr() + (TPF - 1)) {
-                        Some(offset) => offset / TPF,
+                    let offset = match tick_rate.history_offset(
+                        frame_time,
+                        player.move_seq_nr(),
+                        player.rewind_window(),
+                    ) {
+                        Some(offset) => offset,
                         None => {
-                            debug!("Offset rejected because subtraction overflow");
-                            return false;
+                            debug!("Offset rejected because outside of history range");
+                            return ClaimedHit::Rejected;
                         }
                     };
 
-                    if offset >= MOVEMENT_BUFFER_CAP as u32 {
-                        debug!("Offset rejected because outside of history range");
-                        return false;
-                    }
-
                     let index = (MOVEMENT_BUFFER_CAP - 1) - (offset as usize);
                     debug!("collide index {}, offset {}", index, offset);
 
-                    let room_data_for_ball = RoomDataForBall {
-                        delta: TPF as f32,
-                        team_count: shared_data.nr_teams().into(),
-                        power_up: None,
-                        spin_towards_center: shared_data.spin_towards_center(),
-                    };
-
                     for i in (index.saturating_sub(1)..=index).rev() {
                         if ball.has_collision(i) {
                             debug!("Early escape because collision already ACK'd");
-                            break;
+                            return ClaimedHit::AlreadyAcked;
                         }
 
                         if let (BallTickResult::Bounce, _, rewritten_history) = ball.tick_no_update(
@@ -173,12 +231,12 @@ impl PlayerContainer {
                                 );
                             }
 
-                            return true;
+                            return ClaimedHit::Rewound;
                         }
                     }
 
                     // DEBUG
-                    for i in 0..MOVEMENT_BUFFER_CAP {
+                    for i in (MOVEMENT_BUFFER_CAP - history_len)..MOVEMENT_BUFFER_CAP {
                         debug!(
                             "  test {} {:?}",
                             i,
@@ -193,20 +251,31 @@ impl PlayerContainer {
                         );
                     }
 
-                    false
+                    ClaimedHit::Rejected
                 };
 
-                if collides() {
+                let claimed_hit = collides();
+                if claimed_hit == ClaimedHit::Rewound {
                     // Yes, something did happen in the past we didn't see!
-                    ball_syncs.push(BallSync::new(ball_hit.id(), 1, &ball));
+                    ball_syncs.push(BallSync::new(ball_hit.id(), 1, ball));
                     debug!("queued a correction");
                 }
+                Some(claimed_hit)
             } else {
+                let claimed_hit = player.ball_hit().map(|_| ClaimedHit::Rejected);
                 // Necessary to sync up dropping.
                 drop(player);
-            }
+                claimed_hit
+            };
 
             let mut player = player_refcell.borrow_mut();
+            if let Some(claimed_hit) = claimed_hit {
+                let stats = player.stats_mut();
+                stats.record_claimed_hit();
+                if claimed_hit != ClaimedHit::Rejected {
+                    stats.record_confirmed_hit();
+                }
+            }
             player.reset_ball_hit();
         }
 
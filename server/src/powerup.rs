@@ -1,37 +1,263 @@
 use crate::ball::BALL_RADIUS;
+use crate::protocol::serialize_if_supported;
+use crate::room::MAX_BALLS;
 use crate::vector::Vector;
-use serde::Serialize;
+use crate::version::Features;
+use serde::{Deserialize, Serialize, Serializer};
 
 /// A line segment has no width, so we need to extend the powerup size with the ball radius as padding.
 const POWERUP_PADDING: f32 = BALL_RADIUS;
 /// Radius of powerup circle.
 pub const POWERUP_SIZE: f32 = 16.0 + POWERUP_PADDING;
 
-#[derive(Debug, Copy, Clone, Serialize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[repr(u8)]
 pub enum PowerUpEffectType {
     GrowOwnTeam,
     BonusPoints,
     SplitRGB,
     RotateField,
     SlowDown,
+    Multiball,
+    ShrinkOpponents,
+    SpeedUpBalls,
+    ShieldWall,
+    ReverseControls,
 }
 
+/// Which teams a power-up effect applies to.
+#[derive(Debug, Copy, Clone)]
+pub enum PowerUpTarget {
+    /// The activating team.
+    Own,
+    /// Every team except the activating team.
+    Opponents,
+    /// The team that conceded the last point, unless that's the activating team.
+    LastConceded,
+    /// A random team except the activating team.
+    RandomOpponent,
+}
+
+/// Spawn rules and timings of a power-up effect.
+#[derive(Debug)]
+pub struct PowerUpDefinition {
+    pub effect_type: PowerUpEffectType,
+    pub target: PowerUpTarget,
+    /// Relative chance of being picked amongst the eligible power-ups.
+    pub weight: u32,
+    /// Time in seconds before the power-up spawns on the field.
+    pub spawn_delay: f32,
+    /// Time in seconds the effect lasts.
+    pub duration: f32,
+    /// Only spawn if there are at least this many teams.
+    pub min_teams: u8,
+    /// Only spawn if there are at most this many balls on the field.
+    pub max_balls: u8,
+}
+
+/// All power-up definitions, indexed by `PowerUpEffectType`.
+pub const POWER_UP_DEFINITIONS: [PowerUpDefinition; PowerUpEffectType::COUNT] = [
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::GrowOwnTeam,
+        target: PowerUpTarget::Own,
+        weight: 4,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::BonusPoints,
+        target: PowerUpTarget::Own,
+        weight: 4,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::SplitRGB,
+        target: PowerUpTarget::Own,
+        weight: 4,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::RotateField,
+        target: PowerUpTarget::Own,
+        weight: 4,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::SlowDown,
+        target: PowerUpTarget::Opponents,
+        weight: 4,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::Multiball,
+        target: PowerUpTarget::Own,
+        weight: 3,
+        spawn_delay: 15.0,
+        duration: 15.0,
+        min_teams: 2,
+        // The extra ball must still fit in the ball limit.
+        max_balls: MAX_BALLS - 1,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::ShrinkOpponents,
+        target: PowerUpTarget::Opponents,
+        weight: 3,
+        spawn_delay: 15.0,
+        duration: 10.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::SpeedUpBalls,
+        target: PowerUpTarget::Own,
+        weight: 3,
+        spawn_delay: 15.0,
+        duration: 8.0,
+        min_teams: 2,
+        max_balls: 4,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::ShieldWall,
+        target: PowerUpTarget::Own,
+        weight: 2,
+        spawn_delay: 20.0,
+        duration: 6.0,
+        // Shielding one of two teams makes the match too one-sided.
+        min_teams: 3,
+        max_balls: MAX_BALLS,
+    },
+    PowerUpDefinition {
+        effect_type: PowerUpEffectType::ReverseControls,
+        target: PowerUpTarget::LastConceded,
+        weight: 2,
+        spawn_delay: 15.0,
+        duration: 6.0,
+        min_teams: 2,
+        max_balls: MAX_BALLS,
+    },
+];
+
+impl PowerUpEffectType {
+    /// Number of power-up effect types.
+    pub const COUNT: usize = 10;
+
+    /// Gets the definition of this effect type.
+    #[inline]
+    pub fn definition(self) -> &'static PowerUpDefinition {
+        &POWER_UP_DEFINITIONS[self as usize]
+    }
+}
+
+/// Set of power-up effect types, e.g. the ones enabled in a room.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[repr(transparent)]
+pub struct PowerUpSet(u16);
+
+const_assert!(PowerUpEffectType::COUNT <= 16);
+
+impl PowerUpSet {
+    /// Set with every power-up effect type.
+    pub const fn all() -> Self {
+        Self((1 << PowerUpEffectType::COUNT) - 1)
+    }
+
+    /// Set with the effect types up to slow down, the ones every client knows.
+    pub const fn original() -> Self {
+        Self((1 << (PowerUpEffectType::SlowDown as u16 + 1)) - 1)
+    }
+
+    /// Gets the effect types that are in both sets.
+    #[inline]
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Checks if the set contains the effect type.
+    #[inline]
+    pub fn contains(self, effect_type: PowerUpEffectType) -> bool {
+        self.0 & (1 << effect_type as u16) != 0
+    }
+
+    /// Checks if the set is empty.
+    #[inline]
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Checks if the set only contains existing effect types.
+    #[inline]
+    pub fn is_valid(self) -> bool {
+        self.0 & !Self::all().0 == 0
+    }
+}
+
+/// How often power-ups spawn.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PowerUpFrequency {
+    Rare,
+    Normal,
+    Frequent,
+}
+
+impl PowerUpFrequency {
+    /// Factor to apply to the spawn delay of the power-up definitions.
+    pub fn spawn_delay_factor(self) -> f32 {
+        match self {
+            Self::Rare => 2.0,
+            Self::Normal => 1.0,
+            Self::Frequent => 0.5,
+        }
+    }
+}
+
+/// Identifies a power-up from its spawn until its effect ends.
+pub type PowerUpId = u16;
+
 #[derive(Debug, Copy, Clone)]
 pub struct PowerUpEffect {
+    pub power_up_id: PowerUpId,
     pub effect_type: PowerUpEffectType,
     pub activating_team: u8,
 }
 
+/// Serializes the id of a power-up, clients that only know one power-up at a time don't need it.
+fn serialize_power_up_id<S: Serializer>(id: &PowerUpId, serializer: S) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(id, Features::MULTIPLE_POWER_UPS, serializer)
+}
+
 #[derive(Debug, Serialize, Copy, Clone)]
 pub struct PowerUp {
+    #[serde(serialize_with = "serialize_power_up_id")]
+    id: PowerUpId,
     pos: Vector,
     effect: PowerUpEffectType,
 }
 
 impl PowerUp {
     /// Creates a new power-up.
-    pub fn new(pos: Vector, effect: PowerUpEffectType) -> Self {
-        Self { pos, effect }
+    pub fn new(id: PowerUpId, pos: Vector, effect: PowerUpEffectType) -> Self {
+        Self { id, pos, effect }
+    }
+
+    /// Power-up id.
+    #[inline]
+    pub fn id(&self) -> PowerUpId {
+        self.id
     }
 
     /// Power-up effect.
@@ -59,3 +285,43 @@ impl PowerUp {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definitions_are_indexed_by_effect_type() {
+        for (i, definition) in POWER_UP_DEFINITIONS.iter().enumerate() {
+            assert_eq!(definition.effect_type as usize, i);
+            assert_eq!(
+                definition.effect_type.definition().effect_type,
+                definition.effect_type
+            );
+        }
+    }
+
+    #[test]
+    fn all_set_contains_every_effect_type() {
+        for definition in POWER_UP_DEFINITIONS.iter() {
+            assert!(PowerUpSet::all().contains(definition.effect_type));
+        }
+        assert!(PowerUpSet::all().is_valid());
+    }
+
+    #[test]
+    fn original_set_stops_at_slow_down() {
+        let original = PowerUpSet::all().intersection(PowerUpSet::original());
+        assert!(original.contains(PowerUpEffectType::GrowOwnTeam));
+        assert!(original.contains(PowerUpEffectType::SlowDown));
+        assert!(!original.contains(PowerUpEffectType::Multiball));
+        assert!(!original.contains(PowerUpEffectType::ReverseControls));
+    }
+
+    #[test]
+    fn set_with_unknown_effect_types_is_invalid() {
+        assert!(!PowerUpSet(1 << PowerUpEffectType::COUNT).is_valid());
+        assert!(PowerUpSet(0).is_valid());
+        assert!(PowerUpSet(0).is_empty());
+    }
+}
@@ -1,12 +1,19 @@
-use crate::ball::{Ball, HitPair};
+use crate::ball::{Ball, HitPair, NO_TEAM};
+use crate::latency::PingToken;
 use crate::player::{Client, ClientId, SeqNr};
-use crate::powerup::PowerUp;
-use crate::room::MatchTime;
+use crate::powerup::{PowerUp, PowerUpFrequency, PowerUpId, PowerUpSet};
+use crate::quantize::{is_compact, serialize_client_syncs, CompactBallData};
+use crate::rate_limit::MessageCategory;
+use crate::room::{FrameTime, MatchTime, TickRate};
 use crate::shared_room_data::SharedRoomData;
+use crate::snapshot::DeltaSyncMessage;
+use crate::team_data::TeamMask;
 use crate::vector::Vector;
+use crate::version::{codec_version, Features, ProtocolVersion};
 use futures::channel::oneshot;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use smallvec::SmallVec;
+use tokio::time::Instant;
 
 #[derive(Serialize, Copy, Clone)]
 pub struct Join<'a> {
@@ -49,13 +56,23 @@ pub struct StartState {
     pub w_angle: f32,
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy)]
 pub struct BallData {
     pub pos: Vector,
     pub dir: Vector,
     pub spin: f32,
 }
 
+impl Serialize for BallData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if is_compact() {
+            CompactBallData::from(self).serialize(serializer)
+        } else {
+            (self.pos, self.dir, self.spin).serialize(serializer)
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct Start<'a> {
     pub team_count: u8,
@@ -86,10 +103,41 @@ impl BallSync {
 
 #[derive(Serialize)]
 pub struct SyncMessage {
-    pub frame_nr: f32,
+    #[serde(serialize_with = "serialize_frame_time")]
+    pub frame_time: FrameTime,
+    #[serde(serialize_with = "serialize_client_syncs")]
     pub client_syncs: Vec<ClientSync>,
     pub ball_syncs: SmallVec<[BallSync; 3]>,
-    pub power_up: PowerUpPacket,
+    #[serde(serialize_with = "serialize_power_up_events")]
+    pub power_ups: SmallVec<[PowerUpEvent; 2]>,
+}
+
+/// Serializes the power-up events of a sync.
+/// Clients without `Features::MULTIPLE_POWER_UPS` get one packet, the last one like they used to.
+fn serialize_power_up_events<S: Serializer>(
+    events: &SmallVec<[PowerUpEvent; 2]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if codec_version().supports(Features::MULTIPLE_POWER_UPS) {
+        events.serialize(serializer)
+    } else {
+        events
+            .last()
+            .map_or(&PowerUpPacket::None, |event| &event.packet)
+            .serialize(serializer)
+    }
+}
+
+/// Serializes a match time, older clients get a frame number as float.
+pub fn serialize_frame_time<S: Serializer>(
+    frame_time: &FrameTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if codec_version().supports(Features::CLOCK_SYNC) {
+        frame_time.serialize(serializer)
+    } else {
+        (*frame_time as f32 / 1000.0).serialize(serializer)
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -99,6 +147,7 @@ pub struct RebalanceTeam {
     pub w_angle: f32,
 }
 
+/// Power-up packets. The first field is the activating team, unless it's the targeted teams.
 #[derive(Debug, Serialize)]
 pub enum PowerUpPacket {
     None,
@@ -107,10 +156,52 @@ pub enum PowerUpPacket {
     BonusPoints(u8),
     SplitRGB(u8),
     RotateField(u8),
-    SlowDown(u8, f32),
+    SlowDown(TargetTeams, f32),
+    Multiball(u8, BallData),
+    RemoveBall(u8),
+    ResizeTeams(u8, Vec<(u8, RebalanceTeam)>),
+    SpeedUpBalls(u8, f32),
+    ShieldWall(TargetTeams, bool),
+    ReverseControls(TargetTeams, bool),
 }
 
-#[derive(Serialize)]
+/// Teams a power-up effect applies to.
+/// Clients without `Features::TEAM_MASKS` get the activating team and work out the targets.
+#[derive(Debug, Copy, Clone)]
+pub struct TargetTeams {
+    pub activating_team: u8,
+    pub mask: TeamMask,
+}
+
+impl Serialize for TargetTeams {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if codec_version().supports(Features::TEAM_MASKS) {
+            self.mask.serialize(serializer)
+        } else {
+            self.activating_team.serialize(serializer)
+        }
+    }
+}
+
+/// Pause state changes of a running match.
+#[derive(Debug, Serialize, Copy, Clone)]
+pub enum PausePacket {
+    /// The match got paused by the given client.
+    Paused(ClientId),
+    /// The match resumes after the given countdown (in seconds).
+    Resuming(f32),
+    /// The match is running again.
+    Resumed,
+}
+
+/// A power-up packet, tagged with the id of the power-up it belongs to.
+#[derive(Debug, Serialize)]
+pub struct PowerUpEvent {
+    pub id: PowerUpId,
+    pub packet: PowerUpPacket,
+}
+
+#[derive(Debug, Serialize)]
 pub enum OutdatedReason {
     Client,
     Server,
@@ -132,6 +223,68 @@ pub enum MessageToClient<'a> {
     Outdated(OutdatedReason),
     UpdateSettings(UpdateSettings),
     ResetRoom,
+    Pause(PausePacket),
+    /// The room crashed, the client is back in the lobby.
+    RoomClosed,
+    /// The protocol version used for this connection and the features it has.
+    Negotiated(ProtocolVersion, Features),
+    /// Sync relative to an acknowledged frame, replaces `Sync` when delta syncs are supported.
+    DeltaSync(&'a DeltaSyncMessage<'a>),
+    /// Measures the round trip time, the client answers with `Pong`.
+    Ping(PingToken),
+    /// Round trip times of the players in the room.
+    Latencies(&'a [PlayerLatency]),
+    /// Answer to a clock sync request.
+    ClockSync(ClockSyncReply),
+    /// The room is on another node, the client reconnects to this address and joins again.
+    Redirect(&'a str),
+    /// Answer to `QueryRooms` and `SubscribeRooms`.
+    RoomPage(RoomPage<'a>),
+    /// Changes to the subscribed page since it was last sent.
+    RoomListUpdate(RoomListUpdate<'a>),
+    /// Waiting for a quick-play match, with the number of players in the queue.
+    Queued(u16),
+}
+
+/// A page of the waiting rooms that match a query.
+#[derive(Serialize)]
+pub struct RoomPage<'a> {
+    pub page: u16,
+    /// Number of waiting rooms that match the filter, on all pages.
+    pub matching: u32,
+    pub playing: usize,
+    pub rooms: &'a [&'a (String, SharedRoomData)],
+}
+
+/// Changes to a subscribed page, the client sorts the rooms itself.
+#[derive(Serialize)]
+pub struct RoomListUpdate<'a> {
+    pub matching: u32,
+    pub playing: usize,
+    /// Rooms that are new on the page, or whose data changed.
+    pub changed: Vec<&'a (String, SharedRoomData)>,
+    /// Codes of the rooms that left the page.
+    pub removed: Vec<String>,
+}
+
+/// A sample of the match clock, like in NTP.
+/// The offset to the client clock is `((received - client_time) + (sent - now)) / 2`,
+/// after converting both clocks to the same unit.
+#[derive(Serialize, Copy, Clone)]
+pub struct ClockSyncReply {
+    /// The time the client put in the request, in its own clock.
+    pub client_time: u32,
+    /// Match time when the request arrived.
+    pub received: FrameTime,
+    /// Match time when the reply was sent.
+    pub sent: FrameTime,
+}
+
+#[derive(Serialize, Copy, Clone)]
+pub struct PlayerLatency {
+    pub client_id: ClientId,
+    /// Round trip time in milliseconds.
+    pub rtt: u16,
 }
 
 #[derive(Debug, Deserialize)]
@@ -153,6 +306,92 @@ pub struct UpdateSettings {
     pub power_ups: bool,
     pub match_time: MatchTime,
     pub spin_towards_center: bool,
+    /// Only exists with `Features::POWER_UP_SETTINGS`, older clients get every power-up.
+    #[serde(
+        serialize_with = "serialize_power_up_set",
+        deserialize_with = "deserialize_power_up_set"
+    )]
+    pub enabled_power_ups: PowerUpSet,
+    /// Only exists with `Features::POWER_UP_SETTINGS`, older clients get the normal frequency.
+    #[serde(
+        serialize_with = "serialize_power_up_frequency",
+        deserialize_with = "deserialize_power_up_frequency"
+    )]
+    pub power_up_frequency: PowerUpFrequency,
+    /// Only exists with `Features::TICK_RATE`, older clients play at the default rate.
+    #[serde(
+        serialize_with = "serialize_tick_rate",
+        deserialize_with = "deserialize_tick_rate"
+    )]
+    pub tick_rate: TickRate,
+}
+
+/// Serializes a value if the client has the feature it belongs to.
+pub fn serialize_if_supported<T: Serialize, S: Serializer>(
+    value: &T,
+    feature: Features,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if codec_version().supports(feature) {
+        value.serialize(serializer)
+    } else {
+        // Takes no bytes.
+        serializer.serialize_unit()
+    }
+}
+
+/// Deserializes a value if the client has the feature it belongs to, or uses the fallback.
+pub fn deserialize_if_supported<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    feature: Features,
+    fallback: T,
+    deserializer: D,
+) -> Result<T, D::Error> {
+    if codec_version().supports(feature) {
+        T::deserialize(deserializer)
+    } else {
+        <()>::deserialize(deserializer).map(|()| fallback)
+    }
+}
+
+fn serialize_power_up_set<S: Serializer>(
+    set: &PowerUpSet,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(set, Features::POWER_UP_SETTINGS, serializer)
+}
+
+fn deserialize_power_up_set<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<PowerUpSet, D::Error> {
+    deserialize_if_supported(Features::POWER_UP_SETTINGS, PowerUpSet::all(), deserializer)
+}
+
+fn serialize_power_up_frequency<S: Serializer>(
+    frequency: &PowerUpFrequency,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(frequency, Features::POWER_UP_SETTINGS, serializer)
+}
+
+fn deserialize_power_up_frequency<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<PowerUpFrequency, D::Error> {
+    deserialize_if_supported(
+        Features::POWER_UP_SETTINGS,
+        PowerUpFrequency::Normal,
+        deserializer,
+    )
+}
+
+fn serialize_tick_rate<S: Serializer>(
+    tick_rate: &TickRate,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(tick_rate, Features::TICK_RATE, serializer)
+}
+
+fn deserialize_tick_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TickRate, D::Error> {
+    deserialize_if_supported(Features::TICK_RATE, TickRate::default(), deserializer)
 }
 
 #[derive(Deserialize)]
@@ -163,17 +402,103 @@ pub enum RoomMessageFromClient {
     UpdateSettings(UpdateSettings),
     AddBot,
     RemoveBot,
+    Pause,
+    Resume,
+    /// The client received the delta sync of this frame.
+    AckSync(SeqNr),
+    /// Answer to a `Ping`.
+    Pong(PingToken),
+    /// Asks for a sample of the match clock, with the current client time.
+    /// Clients sample when they join, when a match starts or resumes, and now and then during play.
+    ClockSync(u32),
+}
+
+impl RoomMessageFromClient {
+    /// Gets the rate limit category, leaving and hits are never limited.
+    pub fn category(&self) -> Option<MessageCategory> {
+        match self {
+            // A dropped hit would be missed by the server, the move queue bounds those.
+            RoomMessageFromClient::Move(update) if update.ball_hit != NO_TEAM => None,
+            RoomMessageFromClient::Move(_) => Some(MessageCategory::Move),
+            RoomMessageFromClient::AckSync(_)
+            | RoomMessageFromClient::Pong(_)
+            | RoomMessageFromClient::ClockSync(_) => Some(MessageCategory::Ack),
+            RoomMessageFromClient::Leave => None,
+            _ => Some(MessageCategory::Room),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub enum LobbyMessageFromClient {
     CreateRoom,
     JoinRoom(String),
+    /// Lists the first waiting rooms, replaced by `QueryRooms` with `Features::ROOM_QUERY`.
     ListRooms,
+    /// Gets a page of waiting rooms.
+    QueryRooms(RoomQuery),
+    /// Gets a page of waiting rooms, and changes to it until unsubscribed or in a room.
+    SubscribeRooms(RoomQuery),
+    UnsubscribeRooms,
+    /// Plays with anyone, in a quick-play room that starts by itself.
+    QuickPlay,
+    LeaveQueue,
+}
+
+impl LobbyMessageFromClient {
+    /// Gets the rate limit category, leaving a subscription or the queue is never limited.
+    pub fn category(&self) -> Option<MessageCategory> {
+        match self {
+            LobbyMessageFromClient::UnsubscribeRooms | LobbyMessageFromClient::LeaveQueue => None,
+            _ => Some(MessageCategory::Lobby),
+        }
+    }
+
+    /// Gets the answer to a rate limited request.
+    /// None if the request can't fail, those are skipped: the lobby refreshes the list on a timer.
+    pub fn rate_limited_reply(&self) -> Option<MessageToClient<'static>> {
+        match self {
+            LobbyMessageFromClient::CreateRoom => Some(MessageToClient::TooManyRooms),
+            LobbyMessageFromClient::JoinRoom(_) | LobbyMessageFromClient::QuickPlay => {
+                Some(MessageToClient::JoinRoomError)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Which waiting rooms to list, and in what order.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct RoomQuery {
+    pub page: u16,
+    pub filter: RoomFilter,
+    pub sort: RoomSort,
+}
+
+/// Conditions a listed room has to meet, None matches anything.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct RoomFilter {
+    pub free_slots: bool,
+    pub power_ups: Option<bool>,
+    pub balls: Option<u8>,
+    pub match_time: Option<MatchTime>,
+    /// Part of the room name, ignoring case.
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Default, Copy, Clone, Eq, PartialEq)]
+pub enum RoomSort {
+    /// Most players first.
+    #[default]
+    Players,
+    Name,
+    /// Fewest players first, for a quieter game.
+    FewestPlayers,
 }
 
 #[derive(Deserialize)]
 pub enum LoginMessageFromClient {
+    /// Newest protocol version the client speaks, and the player name.
     SetName(u32, String),
 }
 
@@ -181,17 +506,27 @@ pub enum LoginMessageFromClient {
 #[derive(Debug)]
 pub struct JoinData {
     pub id: ClientId,
+    /// Hands the client back if the room gets torn down.
+    pub closed: oneshot::Receiver<Client>,
 }
 
 /// Game processing inbox.
 /// This is a send-receive channel to communicate between player async managers and the room async manager.
 #[derive(Debug)]
 pub enum MessageToInbox {
-    JoinPlayer(oneshot::Sender<JoinData>, Client, String),
+    /// The room answers with the join data, or hands the client back if it can't join.
+    JoinPlayer(oneshot::Sender<Result<JoinData, Client>>, Client, String),
     RemovePlayer(oneshot::Sender<Client>, ClientId),
     MovePlayer(ClientId, ClientMoveUpdate),
     Start(ClientId),
     UpdateSettings(ClientId, UpdateSettings),
     AddBot(ClientId),
     RemoveBot(ClientId),
+    Pause(ClientId),
+    Resume(ClientId),
+    AckSync(ClientId, SeqNr),
+    /// A pong from a client and when it arrived.
+    Pong(ClientId, PingToken, Instant),
+    /// A clock sync request from a client and when it arrived.
+    ClockSync(ClientId, u32, Instant),
 }
@@ -0,0 +1,211 @@
+use crate::config::env_or;
+use crate::stream::PeerAddr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+/// Comma separated list of IP addresses.
+#[derive(Default)]
+pub struct IpList(Vec<IpAddr>);
+
+impl FromStr for IpList {
+    type Err = <IpAddr as FromStr>::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(IpAddr::from_str)
+            .collect::<Result<_, _>>()
+            .map(IpList)
+    }
+}
+
+/// Settings for running behind reverse proxies.
+pub struct ProxyConfig {
+    /// Proxies whose `X-Forwarded-For` headers and PROXY protocol headers are believed.
+    pub trusted_proxies: IpList,
+    /// Every connection starts with a PROXY protocol (v1 or v2) header.
+    pub proxy_protocol: bool,
+}
+
+impl ProxyConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            trusted_proxies: env_or("PINGBONG_TRUSTED_PROXIES", IpList::default()),
+            proxy_protocol: env_or("PINGBONG_PROXY_PROTOCOL", false),
+        }
+    }
+
+    /// Checks if a peer is a trusted proxy.
+    /// Only local processes can connect over a Unix socket, so those are always trusted.
+    pub fn is_trusted(&self, peer: PeerAddr) -> bool {
+        match peer {
+            PeerAddr::Ip(ip) => self.trusted_proxies.0.contains(&ip),
+            PeerAddr::Unix => true,
+        }
+    }
+
+    /// Gets the client address from an `X-Forwarded-For` header sent by `peer`.
+    /// Walks the list from the right and returns the first address that is not a trusted proxy.
+    pub fn forwarded_for(&self, peer: PeerAddr, header: &str) -> Option<IpAddr> {
+        if !self.is_trusted(peer) {
+            return None;
+        }
+
+        let mut client = None;
+        for ip in header.rsplit(',') {
+            let ip = IpAddr::from_str(ip.trim()).ok()?;
+            client = Some(ip);
+            if !self.is_trusted(PeerAddr::Ip(ip)) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// Reads a PROXY protocol header from the start of a stream.
+/// Returns the source address, which is None for local connections (health checks).
+pub async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<IpAddr>> {
+    // The shortest v1 header is longer than this, so we never read past the header.
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(header[0], header[1], &body).ok_or_else(invalid_header)
+    } else if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid_header());
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line).ok_or_else(invalid_header)
+    } else {
+        Err(invalid_header())
+    }
+}
+
+fn invalid_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY header")
+}
+
+/// Parses a v1 header line, including the CRLF.
+fn parse_v1(line: &[u8]) -> Option<Option<IpAddr>> {
+    let line = std::str::from_utf8(line).ok()?.strip_suffix("\r\n")?;
+    let mut parts = line.split(' ');
+    if parts.next()? != "PROXY" {
+        return None;
+    }
+
+    match parts.next()? {
+        "UNKNOWN" => Some(None),
+        "TCP4" => Some(Some(IpAddr::V4(parts.next()?.parse().ok()?))),
+        "TCP6" => Some(Some(IpAddr::V6(parts.next()?.parse().ok()?))),
+        _ => None,
+    }
+}
+
+/// Parses a v2 header given the version/command byte, the family byte and the address block.
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Option<Option<IpAddr>> {
+    if version_command >> 4 != 2 {
+        return None;
+    }
+
+    match version_command & 0x0f {
+        // LOCAL: sent by the proxy itself.
+        0 => Some(None),
+        // PROXY
+        1 => match family >> 4 {
+            // AF_INET
+            1 if body.len() >= 12 => {
+                let mut ip = [0u8; 4];
+                ip.copy_from_slice(&body[..4]);
+                Some(Some(IpAddr::V4(Ipv4Addr::from(ip))))
+            }
+            // AF_INET6
+            2 if body.len() >= 36 => {
+                let mut ip = [0u8; 16];
+                ip.copy_from_slice(&body[..16]);
+                Some(Some(IpAddr::V6(Ipv6Addr::from(ip))))
+            }
+            // AF_UNSPEC and AF_UNIX don't carry an IP address.
+            0 | 3 => Some(None),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> PeerAddr {
+        PeerAddr::Ip(ip(s))
+    }
+
+    #[test]
+    fn forwarded_for_skips_trusted_proxies() {
+        let cfg = ProxyConfig {
+            trusted_proxies: "10.0.0.1, 10.0.0.2".parse().unwrap(),
+            proxy_protocol: false,
+        };
+
+        let header = "1.2.3.4, 5.6.7.8, 10.0.0.2";
+        assert_eq!(
+            cfg.forwarded_for(peer("10.0.0.1"), header),
+            Some(ip("5.6.7.8"))
+        );
+        assert_eq!(
+            cfg.forwarded_for(PeerAddr::Unix, header),
+            Some(ip("5.6.7.8"))
+        );
+        assert_eq!(cfg.forwarded_for(peer("5.6.7.8"), header), None);
+        assert_eq!(cfg.forwarded_for(peer("10.0.0.1"), "garbage"), None);
+    }
+
+    #[tokio::test]
+    async fn proxy_headers() {
+        let mut v1: &[u8] = b"PROXY TCP4 1.2.3.4 10.0.0.1 5000 443\r\nGET /";
+        assert_eq!(
+            read_proxy_header(&mut v1).await.unwrap(),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(v1, b"GET /");
+
+        let mut v1: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut v1).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 1, 2, 3, 4, 10, 0, 0, 1, 0x13, 0x88, 1, 0xbb,
+        ]);
+        v2.extend_from_slice(b"GET /");
+        let mut v2 = v2.as_slice();
+        assert_eq!(
+            read_proxy_header(&mut v2).await.unwrap(),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(v2, b"GET /");
+
+        let mut garbage: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_proxy_header(&mut garbage).await.is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::ball::SPIN_MAX;
+use crate::player::ClientId;
+use crate::protocol::{BallData, ClientSync};
+use crate::room::FIELD_WIDTH;
+use crate::version::{codec_version, Features};
+use serde::ser::SerializeSeq;
+use serde::{Serialize, Serializer};
+use std::f32::consts::TAU;
+
+/// Position units per pixel, the error stays below 0.1 pixels.
+/// Positions up to eight field widths away from the origin fit.
+const POS_SCALE: f32 = 4096.0 / FIELD_WIDTH;
+/// Angle units per radian, a full turn wraps around.
+const ANGLE_SCALE: f32 = 65536.0 / TAU;
+/// Speed units per pixel per frame.
+const SPEED_SCALE: f32 = 256.0;
+/// Spin units per radian.
+const SPIN_SCALE: f32 = i16::MAX as f32 / SPIN_MAX;
+
+/// Converts a position coordinate to fixed-point.
+#[inline]
+pub fn quantize_pos(x: f32) -> i16 {
+    (x * POS_SCALE).round() as i16
+}
+
+/// Converts an angle to fixed-point, angles are taken modulo a full turn.
+#[inline]
+pub fn quantize_angle(angle: f32) -> u16 {
+    (angle.rem_euclid(TAU) * ANGLE_SCALE).round() as u32 as u16
+}
+
+/// Converts a speed (pixels per frame) to fixed-point.
+#[inline]
+pub fn quantize_speed(speed: f32) -> u16 {
+    (speed * SPEED_SCALE).round() as u16
+}
+
+/// Converts a spin to fixed-point.
+#[inline]
+pub fn quantize_spin(spin: f32) -> i16 {
+    (spin * SPIN_SCALE).round() as i16
+}
+
+/// Checks if the compact format is in use.
+#[inline]
+pub fn is_compact() -> bool {
+    codec_version().supports(Features::COMPACT_STATE)
+}
+
+/// Compact ball data: fixed-point position and spin, direction as angle and speed.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompactBallData {
+    pub pos: [i16; 2],
+    pub angle: u16,
+    pub speed: u16,
+    pub spin: i16,
+}
+
+impl From<&BallData> for CompactBallData {
+    fn from(data: &BallData) -> Self {
+        Self {
+            pos: [quantize_pos(data.pos.x()), quantize_pos(data.pos.y())],
+            angle: quantize_angle(data.dir.angle()),
+            speed: quantize_speed(data.dir.len()),
+            spin: quantize_spin(data.spin),
+        }
+    }
+}
+
+/// Compact client sync, the sequence number is relative to the previous sync in the list.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct CompactClientSync {
+    pub client_id: ClientId,
+    pub pos: u16,
+    pub seq_nr: i32,
+}
+
+/// Serializes a list of client syncs, in the compact format if it's in use.
+pub fn serialize_client_syncs<S: Serializer>(
+    syncs: &[ClientSync],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if !is_compact() {
+        return syncs.serialize(serializer);
+    }
+
+    let mut seq = serializer.serialize_seq(Some(syncs.len()))?;
+    let mut last_seq_nr = 0;
+    for sync in syncs {
+        seq.serialize_element(&CompactClientSync {
+            client_id: sync.client_id,
+            pos: quantize_angle(sync.pos),
+            seq_nr: sync.seq_nr.wrapping_sub(last_seq_nr) as i32,
+        })?;
+        last_seq_nr = sync.seq_nr;
+    }
+    seq.end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ball::{Ball, BALL_RADIUS, DEFAULT_BALL_SPEED};
+    use crate::protocol::{BallSync, SyncMessage};
+    use crate::room::{TickRate, CIRCLE_RADIUS, FIELD_HEIGHT};
+    use crate::vector::Vector;
+    use crate::version::{with_version, ProtocolVersion};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use smallvec::SmallVec;
+
+    /// Client side collision checks have to agree with the server to a fraction of the ball.
+    const TOLERANCE: f32 = BALL_RADIUS / 32.0;
+
+    fn dequantize(data: CompactBallData) -> BallData {
+        BallData {
+            pos: Vector::new(
+                data.pos[0] as f32 / POS_SCALE,
+                data.pos[1] as f32 / POS_SCALE,
+            ),
+            dir: Vector::from_angle(data.angle as f32 / ANGLE_SCALE)
+                * (data.speed as f32 / SPEED_SCALE),
+            spin: data.spin as f32 / SPIN_SCALE,
+        }
+    }
+
+    #[test]
+    fn round_trip_stays_within_collision_tolerance() {
+        let mut rng = SmallRng::seed_from_u64(0x9a4d);
+        for _ in 0..10_000 {
+            let angle = rng.gen_range(-TAU..TAU);
+            let original = BallData {
+                pos: Vector::new(
+                    rng.gen_range(0.0..FIELD_WIDTH),
+                    rng.gen_range(0.0..FIELD_HEIGHT),
+                ),
+                dir: Vector::from_angle(angle) * rng.gen_range(0.0..4.0 * DEFAULT_BALL_SPEED),
+                spin: rng.gen_range(-SPIN_MAX..SPIN_MAX),
+            };
+            let decoded = dequantize(CompactBallData::from(&original));
+
+            // Where the ball is after the longest server tick, spin ignored.
+            let frames = TickRate::Low.frames_per_tick() as f32;
+            let expected = original.pos + original.dir * frames;
+            let actual = decoded.pos + decoded.dir * frames;
+            assert!((expected - actual).len() < TOLERANCE);
+            assert!((original.spin - decoded.spin).abs() * frames < SPIN_MAX / 1000.0);
+
+            // Player positions are angles on the circle.
+            let decoded_angle = quantize_angle(angle) as f32 / ANGLE_SCALE;
+            let error = (angle.rem_euclid(TAU) - decoded_angle).abs();
+            assert!(error.min(TAU - error) * CIRCLE_RADIUS < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn compact_format_is_opt_in() {
+        let ball = Ball::new(Vector::new(400.0, 400.0), 1.0);
+        let client_sync = |client_id, pos, seq_nr| ClientSync {
+            client_id,
+            pos,
+            seq_nr,
+        };
+        let sync = SyncMessage {
+            frame_time: 0,
+            client_syncs: vec![client_sync(0, 1.0, 90_000), client_sync(1, 2.0, 90_003)],
+            ball_syncs: std::iter::once(BallSync::new(0, 0, &ball)).collect(),
+            power_ups: SmallVec::new(),
+        };
+        let encode = || crate::bincode::serialize(&sync).unwrap().len();
+
+        let full = encode();
+        let compact = with_version(ProtocolVersion::CURRENT, encode);
+        assert!(!is_compact());
+        // Client syncs: varint id, f32 pos, varint seq nr. Ball sync: 3 bytes, then 5 f32.
+        assert_eq!(full, 4 + 1 + 2 * (1 + 4 + 5) + 1 + 3 + 5 * 4 + 1);
+        // Integer match time. Client syncs: u16 pos, the second seq nr is a small delta.
+        // Ball data: 4 u16 and no spin.
+        assert_eq!(
+            compact,
+            1 + 1 + (1 + 3 + 5) + (1 + 3 + 1) + 1 + 3 + (4 * 3 + 1) + 1
+        );
+    }
+}
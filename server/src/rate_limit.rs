@@ -0,0 +1,214 @@
+use crate::config::{config, env_or};
+use crate::stream::PeerAddr;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Limits for client connections.
+pub struct RateLimitConfig {
+    /// Moves per second, the client sends one every two frames of its display.
+    pub moves_per_sec: f32,
+    /// Burst size for moves.
+    pub move_burst: f32,
+    /// Room control messages (start, settings, bots, pause) per second.
+    pub room_per_sec: f32,
+    /// Burst size for room control messages.
+    pub room_burst: f32,
+    /// Login and lobby messages per second.
+    pub lobby_per_sec: f32,
+    /// Burst size for login and lobby messages.
+    pub lobby_burst: f32,
+    /// Sync acknowledgements per second.
+    pub acks_per_sec: f32,
+    /// Burst size for sync acknowledgements.
+    pub ack_burst: f32,
+    /// Maximum number of concurrent connections from an IP address.
+    pub max_connections_per_ip: u32,
+    /// Capacity of a room inbox, senders wait when it's full.
+    pub inbox_capacity: usize,
+    /// Messages queued for a client before it counts as lagging.
+    pub outbox_capacity: usize,
+}
+
+impl RateLimitConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            moves_per_sec: env_or("PINGBONG_RATE_MOVES_PER_SEC", 150.0),
+            move_burst: env_or("PINGBONG_RATE_MOVE_BURST", 75.0),
+            room_per_sec: env_or("PINGBONG_RATE_ROOM_PER_SEC", 5.0),
+            room_burst: env_or("PINGBONG_RATE_ROOM_BURST", 10.0),
+            lobby_per_sec: env_or("PINGBONG_RATE_LOBBY_PER_SEC", 2.0),
+            lobby_burst: env_or("PINGBONG_RATE_LOBBY_BURST", 10.0),
+            acks_per_sec: env_or("PINGBONG_RATE_ACKS_PER_SEC", 40.0),
+            ack_burst: env_or("PINGBONG_RATE_ACK_BURST", 20.0),
+            max_connections_per_ip: env_or("PINGBONG_MAX_CONNECTIONS_PER_IP", 16),
+            inbox_capacity: env_or("PINGBONG_INBOX_CAPACITY", 256),
+            outbox_capacity: env_or("PINGBONG_OUTBOX_CAPACITY", 64),
+        }
+    }
+}
+
+/// Token bucket: allows bursts up to the capacity, refills at a constant rate.
+pub struct TokenBucket {
+    tokens: f32,
+    capacity: f32,
+    refill_per_sec: f32,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(refill_per_sec: f32, capacity: f32) -> Self {
+        Self {
+            tokens: capacity,
+            capacity,
+            refill_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token at the given time, returns false if the bucket is empty.
+    fn take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Takes a token, returns false if the bucket is empty.
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+}
+
+/// Message categories that are limited separately.
+#[derive(Debug, Copy, Clone)]
+pub enum MessageCategory {
+    Move,
+    Room,
+    Lobby,
+    Ack,
+}
+
+/// Per-connection rate limits.
+pub struct RateLimits {
+    moves: TokenBucket,
+    room: TokenBucket,
+    lobby: TokenBucket,
+    acks: TokenBucket,
+}
+
+impl RateLimits {
+    /// Creates the limits from the config.
+    pub fn new() -> Self {
+        let cfg = &config().rate_limit;
+        Self {
+            moves: TokenBucket::new(cfg.moves_per_sec, cfg.move_burst),
+            room: TokenBucket::new(cfg.room_per_sec, cfg.room_burst),
+            lobby: TokenBucket::new(cfg.lobby_per_sec, cfg.lobby_burst),
+            acks: TokenBucket::new(cfg.acks_per_sec, cfg.ack_burst),
+        }
+    }
+
+    /// Checks whether a message of the given category is allowed.
+    pub fn allow(&mut self, category: MessageCategory) -> bool {
+        let allowed = match category {
+            MessageCategory::Move => self.moves.take(),
+            MessageCategory::Room => self.room.take(),
+            MessageCategory::Lobby => self.lobby.take(),
+            MessageCategory::Ack => self.acks.take(),
+        };
+        if !allowed {
+            debug!("rate limited {:?}", category);
+        }
+        allowed
+    }
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<PeerAddr, u32>> = Mutex::new(HashMap::new());
+}
+
+/// Counts a connection from an address for as long as it lives.
+pub struct ConnectionGuard {
+    addr: PeerAddr,
+}
+
+impl ConnectionGuard {
+    /// Registers a connection, returns None if the address has too many connections.
+    pub fn acquire(addr: PeerAddr) -> Option<Self> {
+        let mut connections = CONNECTIONS.lock().expect("connections");
+        let count = connections.entry(addr).or_insert(0);
+        if *count >= config().rate_limit.max_connections_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(Self { addr })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS.lock().expect("connections");
+        if let Some(count) = connections.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ClientMoveUpdate, RoomMessageFromClient};
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        bucket.last_refill = start;
+
+        assert!((0..3).all(|_| bucket.take_at(start)));
+        assert!(!bucket.take_at(start));
+
+        // Half a second gives one token back.
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take_at(later));
+        assert!(!bucket.take_at(later));
+
+        // Never more than the capacity.
+        let much_later = later + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.take_at(much_later)));
+        assert!(!bucket.take_at(much_later));
+    }
+    #[test]
+    fn fast_displays_and_hits_are_not_limited() {
+        let start = Instant::now();
+        let mut limits = RateLimits::new();
+        limits.moves.last_refill = start;
+
+        // A move every two frames of a 240 Hz display.
+        assert!((0..2400).all(|i| limits.moves.take_at(start + Duration::from_secs(i) / 120)));
+
+        let hit = RoomMessageFromClient::Move(ClientMoveUpdate {
+            delta: 0.0,
+            seq_nr: 0,
+            ball_hit: 0,
+            spin: 0.0,
+        });
+        assert!(hit.category().is_none());
+    }
+}
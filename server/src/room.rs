@@ -1,90 +1,230 @@
-use crate::ball::{Ball, BallTickResult, RoomDataForBall, BALL_RADIUS, MOVEMENT_BUFFER_CAP};
+use crate::ball::{
+    Ball, BallTickResult, RoomDataForBall, BALL_RADIUS, MOVEMENT_BUFFER_CAP, NO_TEAM,
+};
 use crate::bot::Bot;
+use crate::config::config;
+use crate::matchmaking::{QUICK_PLAY_JOIN_TIME, QUICK_PLAY_PLAYERS};
 use crate::player::{Client, ClientId, Player, SeqNr};
 use crate::player_container::PlayerContainer;
-use crate::powerup::{PowerUp, PowerUpEffect, PowerUpEffectType};
+use crate::powerup::{
+    PowerUp, PowerUpEffect, PowerUpEffectType, PowerUpId, PowerUpSet, PowerUpTarget,
+};
 use crate::protocol::{
-    BallSync, Join, JoinData, JoinedRoom, Leave, MessageToClient, MessageToInbox,
-    PlayerAlreadyJoinedData, PowerUpPacket, RebalanceTeam, Start, StartState, SyncMessage,
+    BallSync, ClockSyncReply, Join, JoinData, JoinedRoom, Leave, MessageToClient, MessageToInbox,
+    PausePacket, PlayerAlreadyJoinedData, PlayerLatency, PowerUpEvent, PowerUpPacket,
+    RebalanceTeam, Start, StartState, SyncMessage, TargetTeams,
 };
 use crate::room_manager::RoomId;
 use crate::rooms;
 use crate::shared_room_data::SharedRoomData;
-use crate::team_data::SLOWDOWN_FACTOR;
+use crate::snapshot::{DeltaSyncMessage, Snapshot, SyncHistory};
+use crate::team_data::{TeamMask, TeamModifierKind, SLOWDOWN_FACTOR};
 use crate::tracker::Tracker;
 use crate::vector::Vector;
-use bytes::Bytes;
+use crate::version::{EncodeCache, Features, ProtocolVersion};
+use chrono::Local;
 use fnv::FnvHashMap;
-use futures::channel::mpsc::UnboundedReceiver;
-use futures::StreamExt;
+use futures::channel::mpsc::Receiver;
+use futures::channel::oneshot;
+use futures::{FutureExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::{self, Instant, Interval};
 
-pub const TPS: u8 = 20;
-pub const TPF: u32 = 60 / (TPS as u32);
 pub const FIELD_WIDTH: f32 = 800.0;
 pub const FIELD_HEIGHT: f32 = 800.0;
 pub const CIRCLE_RADIUS: f32 = 300.0;
 
 pub const MAX_TEAMS: usize = 5;
 
+/// Players a room has room for, bots included. Players can't join from the lobby beyond this.
+pub const MAX_PLAYERS: u16 = 16;
+
+/// Bots the host can add to a room.
+const MAX_BOTS: usize = 10;
+
+/// Maximum number of balls on the field.
+pub const MAX_BALLS: u8 = 8;
+
 /// Resize factor when taking the resize boost?
 pub const POWER_UP_RESIZE_FACTOR: f32 = 1.75;
 
+/// Ball speed factor when taking the speed up boost.
+pub const POWER_UP_BALL_SPEED_FACTOR: f32 = 1.5;
+
 /// How long is the countdown for the match start?
 const TIME_WAIT_BEFORE_START: f32 = 3.0;
 
 /// How long is the countdown for the match end to return to the room wait screen.
 const TIME_WAIT_BEFORE_RESET: f32 = 5.0;
 
-const TICK_TIME: Duration = Duration::from_millis(1000 / TPS as u64);
+/// How long is the countdown before a paused match continues?
+const TIME_WAIT_BEFORE_RESUME: f32 = 3.0;
+
+/// How long a vote to pause or resume counts towards the majority.
+const PAUSE_VOTE_TIME: Duration = Duration::from_secs(10);
+
+/// Maximum number of power-ups that can be spawned on the field at the same time.
+const MAX_SPAWNED_POWER_UPS: usize = 3;
+
+/// How long to wait before trying again if no power-up was eligible to spawn.
+const POWER_UP_RETRY_TIME: f32 = 5.0;
+
+/// How many frames the sequence number of a move may be ahead of the match clock,
+/// for the error of the clock sync.
+const MAX_FRAMES_AHEAD: SeqNr = 6;
 
-/// Power up state for room.
+/// Match time in thousandths of a frame, a frame is 1/60 of a second.
+pub type FrameTime = u32;
+
+/// How far back in time the ball history reaches, no client can rewind further.
+/// Each client gets a window based on its round trip time, see `LatencyTracker`.
+pub const LATENCY_COMPENSATION_WINDOW: Duration = Duration::from_millis(500);
+
+/// Power up spawn timer for room.
 #[derive(Copy, Clone)]
-enum PowerUpState {
-    DoNothing,
-    WaitUntilSpawn(f32),
-    Spawned(PowerUp),
-    WaitUntilItIsOver(f32, PowerUpEffect),
+struct PowerUpSpawner {
+    time_left: f32,
+    /// None if no power-up was eligible, we'll try again after the timer.
+    next: Option<PowerUpEffectType>,
 }
 
-impl PowerUpState {
-    const fn default_spawn_wait_state() -> Self {
-        Self::WaitUntilSpawn(15.0)
-    }
+/// A power-up effect that is currently active.
+#[derive(Copy, Clone)]
+struct ActivePowerUp {
+    time_left: f32,
+    effect: PowerUpEffect,
+    targets: TeamMask,
+}
 
-    const fn wait_until_over_state(power_up_effect: PowerUpEffect) -> Self {
-        Self::WaitUntilItIsOver(10.0, power_up_effect)
+type PowerUpEvents = SmallVec<[PowerUpEvent; 2]>;
+
+/// Queues a power-up event, unless there's nothing to tell.
+fn push_power_up_event(events: &mut PowerUpEvents, id: PowerUpId, packet: PowerUpPacket) {
+    if !matches!(packet, PowerUpPacket::None) {
+        events.push(PowerUpEvent { id, packet });
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+/// Pause state for room.
+#[derive(Copy, Clone)]
+enum PauseState {
+    Running,
+    /// Paused since the given instant.
+    Paused(Instant),
+    /// Counting down to resume, the instant is still the start of the pause.
+    Resuming(Instant, f32),
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
 pub enum MatchTime {
     Short,
     Long,
 }
 
+/// Server ticks per second. Clients run at 60 frames per second.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickRate {
+    #[default]
+    Low,
+    Medium,
+    High,
+}
+
+impl TickRate {
+    /// All tick rates.
+    pub const ALL: [Self; 3] = [Self::Low, Self::Medium, Self::High];
+
+    /// Gets the number of ticks per second.
+    #[inline]
+    pub const fn ticks_per_sec(self) -> u32 {
+        match self {
+            Self::Low => 20,
+            Self::Medium => 30,
+            Self::High => 60,
+        }
+    }
+
+    /// Gets the number of client frames per tick.
+    #[inline]
+    pub const fn frames_per_tick(self) -> u32 {
+        60 / self.ticks_per_sec()
+    }
+
+    /// Gets the time between ticks.
+    #[inline]
+    pub fn tick_time(self) -> Duration {
+        Duration::from_secs(1) / self.ticks_per_sec()
+    }
+
+    /// Gets the number of ball states to keep for late collisions:
+    /// enough ticks to cover the latency compensation window, and the current state.
+    pub const fn history_len(self) -> usize {
+        self.ticks_in(LATENCY_COMPENSATION_WINDOW) as usize + 1
+    }
+
+    /// Gets the number of ticks needed to cover a duration.
+    pub const fn ticks_in(self, window: Duration) -> u32 {
+        (window.as_millis() as u32 * self.ticks_per_sec()).div_ceil(1000)
+    }
+
+    /// Gets how many ticks before the tick at `frame_time` a move happened,
+    /// None if it's further back than `window` or the history.
+    pub fn history_offset(
+        self,
+        frame_time: SeqNr,
+        move_seq_nr: SeqNr,
+        window: Duration,
+    ) -> Option<u32> {
+        let frames_per_tick = self.frames_per_tick();
+        let offset = frame_time.checked_sub(move_seq_nr + (frames_per_tick - 1))? / frames_per_tick;
+        let max_offset = self.ticks_in(window).min(self.history_len() as u32 - 1);
+        (offset <= max_offset).then_some(offset)
+    }
+}
+
 pub struct Room {
     id: RoomId,
     players: PlayerContainer,
     clients: FnvHashMap<ClientId, Client>,
+    /// Hands clients back to their connection loop when the room gets torn down.
+    closed_txs: FnvHashMap<ClientId, oneshot::Sender<Client>>,
     bots: Vec<Bot>,
     balls: Vec<Ball>,
-    power_up_state: PowerUpState,
+    /// None if power-ups are disabled.
+    power_up_spawner: Option<PowerUpSpawner>,
+    spawned_power_ups: SmallVec<[PowerUp; MAX_SPAWNED_POWER_UPS]>,
+    active_power_ups: Vec<ActivePowerUp>,
+    next_power_up_id: PowerUpId,
+    ball_speed_factor: f32,
+    shielded_teams: TeamMask,
+    last_conceded_team: u8,
     host_client_id: ClientId,
     next_client_id: ClientId,
     is_started: bool,
+    /// Features every client in the match has, set when the match starts.
+    features: Features,
+    pause_state: PauseState,
+    /// Clients that voted to toggle the pause state, with the time of their vote.
+    pause_votes: FnvHashMap<ClientId, Instant>,
     tick_delay: Interval,
+    ping_delay: Interval,
     timer: Instant,
     frame_timer: SeqNr,
+    /// Recent snapshots for delta syncs.
+    sync_history: SyncHistory,
     /// Yeah we have to do this because the actual time can drift from the buffer shifts.
     last_tick_time: Instant,
     seconds_passed_since_start: f32,
     shared_data: Arc<SharedRoomData>,
     tracker: Tracker,
+    /// When a quick-play room starts its match by itself, None for other rooms.
+    quick_play_start: Option<Instant>,
 }
 
 impl Room {
@@ -95,17 +235,32 @@ impl Room {
             id,
             players: PlayerContainer::new(),
             clients: Default::default(),
+            closed_txs: Default::default(),
             bots: Vec::new(),
             balls: Vec::new(),
-            power_up_state: PowerUpState::DoNothing,
+            power_up_spawner: None,
+            spawned_power_ups: SmallVec::new(),
+            active_power_ups: Vec::new(),
+            next_power_up_id: 0,
+            ball_speed_factor: 1.0,
+            shielded_teams: 0,
+            last_conceded_team: NO_TEAM,
             host_client_id: 0,
             next_client_id: 0,
             is_started: false,
-            tick_delay: time::interval(TICK_TIME),
+            features: ProtocolVersion::CURRENT.features(),
+            pause_state: PauseState::Running,
+            pause_votes: Default::default(),
+            tick_delay: time::interval(shared_data.tick_rate().tick_time()),
+            ping_delay: time::interval(config().timeouts.ping_interval),
             timer: now,
             frame_timer: 0,
+            sync_history: Default::default(),
             last_tick_time: now,
             seconds_passed_since_start: 0.0,
+            quick_play_start: shared_data
+                .is_quick_play()
+                .then(|| now + QUICK_PLAY_JOIN_TIME),
             shared_data,
             tracker: Tracker::new(id),
         }
@@ -114,6 +269,8 @@ impl Room {
     /// Resets the room state.
     fn reset(&mut self) {
         self.is_started = false;
+        self.pause_state = PauseState::Running;
+        self.pause_votes.clear();
         self.balls.clear();
         self.tracker.reset();
         self.seconds_passed_since_start = 0.0;
@@ -149,17 +306,75 @@ impl Room {
 
     /// Broadcasts a message to all clients.
     pub async fn broadcast(&mut self, msg: MessageToClient<'_>) {
-        let bytes: Bytes = crate::bincode::serialize(&msg).expect("encode").into();
+        let mut cache = EncodeCache::default();
+        for client in self.clients.values_mut() {
+            client.send_cached(&mut cache, &msg).await;
+        }
+    }
+
+    /// Broadcasts a sync, as a delta to clients that support it.
+    async fn broadcast_sync(&mut self, sync: &SyncMessage) {
+        let current = self.sync_history.latest().expect("snapshot of this frame");
+        let mut full = EncodeCache::default();
+        // One delta per base frame.
+        let mut deltas: SmallVec<[(Option<SeqNr>, DeltaSyncMessage, EncodeCache); 2]> =
+            SmallVec::new();
+
+        for (&id, client) in self.clients.iter_mut() {
+            if !client.version().supports(Features::DELTA_SYNC) {
+                client
+                    .send_cached(&mut full, &MessageToClient::Sync(sync))
+                    .await;
+                continue;
+            }
+
+            let base = self.sync_history.base_for(id);
+            let frame = base.map(Snapshot::frame);
+            let index = match deltas.iter().position(|(f, _, _)| *f == frame) {
+                Some(index) => index,
+                None => {
+                    let delta = DeltaSyncMessage::new(sync, base, current);
+                    deltas.push((frame, delta, EncodeCache::default()));
+                    deltas.len() - 1
+                }
+            };
+            // A later delta replaces this one, unless it has events the client must see.
+            let (_, delta, cache) = &mut deltas[index];
+            let msg = MessageToClient::DeltaSync(delta);
+            if delta.power_ups.is_empty() && delta.balls.iter().all(|b| b.flags == 0) {
+                client.send_droppable(cache, &msg);
+            } else {
+                client.send_cached(cache, &msg).await;
+            }
+        }
+    }
+
+    /// Pings all clients and shares the round trip times measured so far.
+    async fn ping_clients(&mut self) {
         for client in self.clients.values_mut() {
-            client.send_bytes(bytes.clone()).await;
+            client.ping().await;
+        }
+
+        let latencies = self
+            .clients
+            .iter()
+            .filter_map(|(&client_id, client)| {
+                Some(PlayerLatency {
+                    client_id,
+                    rtt: client.rtt()?.as_millis().min(u16::MAX as u128) as u16,
+                })
+            })
+            .collect::<Vec<_>>();
+        if !latencies.is_empty() {
+            self.broadcast(MessageToClient::Latencies(&latencies)).await;
         }
     }
 
     /// Broadcasts a message to all clients except one.
     pub async fn broadcast_except(&mut self, msg: MessageToClient<'_>, except: ClientId) {
-        let bytes: Bytes = crate::bincode::serialize(&msg).expect("encode").into();
+        let mut cache = EncodeCache::default();
         for (_, client) in self.clients.iter_mut().filter(|(&id, _)| id != except) {
-            client.send_bytes(bytes.clone()).await;
+            client.send_cached(&mut cache, &msg).await;
         }
     }
 
@@ -201,14 +416,25 @@ impl Room {
 
         // Auto change settings if necessary.
         self.shared_data.start();
+        self.features = self
+            .clients
+            .values()
+            .fold(ProtocolVersion::CURRENT.features(), |features, client| {
+                features & client.version().features()
+            });
+        if self.shared_data.tick_rate() != TickRate::default()
+            && !self.features.contains(Features::TICK_RATE)
+        {
+            self.shared_data.reset_tick_rate();
+            self.broadcast(MessageToClient::UpdateSettings(self.shared_data.settings()))
+                .await;
+        }
         let nr_teams = self.shared_data.nr_teams();
 
         // Power up start state.
-        self.power_up_state = if self.shared_data.power_ups() {
-            PowerUpState::default_spawn_wait_state()
-        } else {
-            PowerUpState::DoNothing
-        };
+        self.reset_power_ups();
+        // The first syncs of a match are keyframes.
+        self.sync_history.clear();
 
         // Put players in teams if necessary.
         /*if auto_team */
@@ -292,6 +518,9 @@ impl Room {
         .await;
 
         self.balls = balls;
+        if self.shared_data.power_ups() {
+            self.power_up_spawner = Some(self.next_power_up_spawner());
+        }
 
         // Actually start signal.
         // We reset the delay signal to sync up.
@@ -342,9 +571,10 @@ impl Room {
         data
     }
 
-    /// Rebalances a team (e.g. due to a player leaving).
+    /// Rebalances a team (e.g. due to a player leaving or a width modifier).
     fn rebalance_team(&mut self, team_nr: u8) -> Option<RebalanceTeam> {
-        self.resize_team_members(team_nr, 1.0)
+        let width_factor = self.players.team_data(team_nr).width_factor();
+        self.resize_team_members(team_nr, width_factor)
     }
 
     /// Removes a player.
@@ -354,14 +584,23 @@ impl Room {
             .remove(client_id)
             .expect("player should not be removed already");
         self.shared_data.update_player_count(u16::MAX);
+        self.pause_votes.remove(&client_id);
+        self.sync_history.remove(client_id);
         let client = self.clients.remove(&client_id);
+        self.closed_txs.remove(&client_id);
         if self.host_client_id == client_id {
-            let mut rooms = rooms().lock().await;
-            rooms.owner_leave(client.as_ref().expect("owner should have a client").ip());
+            // Quick-play rooms don't count towards the rooms of their host.
+            let counted = !self.shared_data.is_quick_play();
+            let rooms = rooms();
+            if counted {
+                rooms.owner_leave(client.as_ref().expect("owner should have a client").addr());
+            }
             // Handle host migration (only if there are still players).
             if let Some((&id, client)) = self.clients.iter().next() {
                 self.host_client_id = id;
-                let _ = rooms.increase_count(client.ip(), true);
+                if counted {
+                    let _ = rooms.increase_count(client.addr(), true);
+                }
             }
         }
         let rebalance = &if self.is_started {
@@ -380,12 +619,89 @@ impl Room {
 
     /// Reset the tick delay.
     fn reset_delay(&mut self) {
-        self.tick_delay = time::interval(TICK_TIME);
+        self.tick_delay = time::interval(self.shared_data.tick_rate().tick_time());
     }
 
-    /// Gets the current (floating point) frame number.
-    fn frame_nr(&self) -> f32 {
-        self.timer.elapsed().as_secs_f32() * 60.0
+    /// Is the match currently playing (i.e. not in the countdowns before start or after end)?
+    fn is_in_match(&self) -> bool {
+        self.is_started
+            && self.seconds_passed_since_start
+                < TIME_WAIT_BEFORE_START + self.shared_data.match_time_f32()
+    }
+
+    /// Registers a pause or resume request.
+    /// The host decides on its own, other players need a majority vote.
+    /// Votes older than `PAUSE_VOTE_TIME` don't count anymore.
+    /// Returns true if the request passes.
+    fn pause_vote(&mut self, sender: ClientId, now: Instant) -> bool {
+        if sender == self.host_client_id {
+            return true;
+        }
+
+        self.pause_votes
+            .retain(|_, &mut voted| now.saturating_duration_since(voted) < PAUSE_VOTE_TIME);
+        self.pause_votes.insert(sender, now);
+        self.pause_votes.len() * 2 > self.clients.len()
+    }
+
+    /// Pauses the match.
+    async fn pause(&mut self, sender: ClientId) {
+        // Clients that can't pause would keep playing on their own.
+        if !self.features.contains(Features::PAUSE)
+            || !self.is_in_match()
+            || !matches!(self.pause_state, PauseState::Running)
+            || !self.pause_vote(sender, Instant::now())
+        {
+            return;
+        }
+
+        debug!("pause by {}", sender);
+        self.pause_votes.clear();
+        self.pause_state = PauseState::Paused(Instant::now());
+        self.broadcast(MessageToClient::Pause(PausePacket::Paused(sender)))
+            .await;
+    }
+
+    /// Starts the countdown to resume the match.
+    async fn resume(&mut self, sender: ClientId) {
+        let paused_since = match self.pause_state {
+            PauseState::Paused(paused_since) => paused_since,
+            _ => return,
+        };
+
+        if !self.pause_vote(sender, Instant::now()) {
+            return;
+        }
+
+        debug!("resume by {}", sender);
+        self.pause_votes.clear();
+        self.pause_state = PauseState::Resuming(paused_since, TIME_WAIT_BEFORE_RESUME);
+        self.broadcast(MessageToClient::Pause(PausePacket::Resuming(
+            TIME_WAIT_BEFORE_RESUME,
+        )))
+        .await;
+    }
+
+    /// Continues the match after the resume countdown.
+    /// The timers are shifted by the paused duration so that the frame numbers continue where
+    /// they left off and the ball history stays consistent with the move sequence numbers.
+    fn finish_resume(&mut self, paused_since: Instant) {
+        let now = Instant::now();
+        self.timer += now - paused_since;
+        self.reset_delay();
+        self.last_tick_time = now;
+        self.pause_state = PauseState::Running;
+    }
+
+    /// Gets the match time at an instant.
+    /// The clock restarts when a match starts and skips pauses when it resumes.
+    fn frame_time_at(&self, instant: Instant) -> FrameTime {
+        (instant.saturating_duration_since(self.timer).as_micros() * 60 / 1000) as FrameTime
+    }
+
+    /// Gets the current match time.
+    fn frame_time(&self) -> FrameTime {
+        self.frame_time_at(Instant::now())
     }
 
     /// Handle end of match.
@@ -395,7 +711,40 @@ impl Room {
         debug!("end of match");
         self.reset();
         self.broadcast(MessageToClient::ResetRoom).await;
-        rooms().lock().await.unmark_as_playing(self.id);
+        rooms().unmark_as_playing(self.id);
+        if let Some(start) = &mut self.quick_play_start {
+            *start = Instant::now() + config().quick_play.wait;
+        }
+    }
+
+    /// Starts the match of a quick-play room when it's full, or when the players waited long
+    /// enough. Returns false if the room closed because nobody showed up.
+    async fn match_quick_play(&mut self, now: Instant) -> bool {
+        let start = match self.quick_play_start {
+            Some(start) if !self.is_started => start,
+            _ => return true,
+        };
+        let humans = self.players.count() - self.bots.len();
+        if humans < QUICK_PLAY_PLAYERS as usize && now < start {
+            return true;
+        }
+        if humans == 0 {
+            rooms().remove(self.id).await;
+            return false;
+        }
+
+        if config().quick_play.bots {
+            while self.players.count() < QUICK_PLAY_PLAYERS as usize && self.bots.len() < MAX_BOTS {
+                self.add_bot(format!("Bot {}", self.bots.len() + 1)).await;
+            }
+        }
+        if self.players.count() > 1 {
+            rooms().mark_as_playing(self.id);
+            self.start().await;
+        } else {
+            self.quick_play_start = Some(now + config().quick_play.wait);
+        }
+        true
     }
 
     /// Gets the room id.
@@ -404,96 +753,307 @@ impl Room {
         self.id
     }
 
+    /// Gets the mask of all teams.
+    fn all_teams(&self) -> TeamMask {
+        (1 << self.shared_data.nr_teams()) - 1
+    }
+
+    /// Resolves which teams a power-up effect applies to.
+    fn power_up_targets(&mut self, target: PowerUpTarget, activating_team: u8) -> TeamMask {
+        let nr_teams = self.shared_data.nr_teams();
+        let own = 1 << activating_team;
+        match target {
+            PowerUpTarget::Own => own,
+            PowerUpTarget::Opponents => self.all_teams() & !own,
+            PowerUpTarget::LastConceded
+                if self.last_conceded_team < nr_teams
+                    && self.last_conceded_team != activating_team =>
+            {
+                1 << self.last_conceded_team
+            }
+            // Fall back to a random opponent if nobody else conceded yet.
+            PowerUpTarget::LastConceded | PowerUpTarget::RandomOpponent => self
+                .tracker
+                .random_other_team(nr_teams, activating_team)
+                .map(|t| 1 << t)
+                .unwrap_or(0),
+        }
+    }
+
+    /// Rebalances the targeted teams after their width modifiers changed.
+    fn rebalance_teams(&mut self, activating_team: u8, targets: TeamMask) -> PowerUpPacket {
+        if targets == 1 << activating_team {
+            return self
+                .rebalance_team(activating_team)
+                .map(|data| PowerUpPacket::ResizePlayers(activating_team, data))
+                .unwrap_or(PowerUpPacket::None);
+        }
+
+        let teams = (0..self.shared_data.nr_teams())
+            .filter(|t| targets & (1 << t) != 0)
+            .filter_map(|t| self.rebalance_team(t).map(|data| (t, data)))
+            .collect::<Vec<_>>();
+        PowerUpPacket::ResizeTeams(activating_team, teams)
+    }
+
+    /// Recalculates the room-wide modifiers of the active power-ups.
+    /// Team modifiers are kept by the teams themselves.
+    fn refresh_power_up_modifiers(&mut self) {
+        self.ball_speed_factor = 1.0;
+        self.shielded_teams = 0;
+
+        for active in self.active_power_ups.iter() {
+            match active.effect.effect_type {
+                PowerUpEffectType::SpeedUpBalls => {
+                    self.ball_speed_factor = POWER_UP_BALL_SPEED_FACTOR;
+                }
+                PowerUpEffectType::ShieldWall => {
+                    self.shielded_teams |= active.targets;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Resets all power-up state.
+    fn reset_power_ups(&mut self) {
+        self.power_up_spawner = None;
+        self.spawned_power_ups.clear();
+        self.active_power_ups.clear();
+        self.players.reset_team_data();
+        self.last_conceded_team = NO_TEAM;
+        self.refresh_power_up_modifiers();
+    }
+
+    /// Creates a new power-up id.
+    fn create_power_up_id(&mut self) -> PowerUpId {
+        let id = self.next_power_up_id;
+        self.next_power_up_id = self.next_power_up_id.wrapping_add(1);
+        id
+    }
+
+    /// Picks the next power-up to spawn.
+    fn next_power_up_spawner(&mut self) -> PowerUpSpawner {
+        // Spawned multiball power-ups will add a ball later on.
+        let pending_balls = self
+            .spawned_power_ups
+            .iter()
+            .filter(|p| p.effect_type() == PowerUpEffectType::Multiball)
+            .count();
+        let mut enabled = self.shared_data.enabled_power_ups();
+        if !self.features.contains(Features::POWER_UP_EFFECTS) {
+            // Older clients can't show the newer effects.
+            enabled = enabled.intersection(PowerUpSet::original());
+        }
+        let next = self.tracker.next_powerup_type(
+            enabled,
+            self.shared_data.nr_teams(),
+            self.balls.len() + pending_balls,
+        );
+        PowerUpSpawner {
+            time_left: next
+                .map(|t| {
+                    t.definition().spawn_delay
+                        * self.shared_data.power_up_frequency().spawn_delay_factor()
+                })
+                .unwrap_or(POWER_UP_RETRY_TIME),
+            next,
+        }
+    }
+
     /// Handle power up effect.
-    fn handle_power_up(&mut self, power_up_effect: PowerUpEffect) -> PowerUpPacket {
-        self.power_up_state = PowerUpState::wait_until_over_state(power_up_effect);
-        debug!("Handle power-up effect: {:?}", power_up_effect);
+    fn handle_power_up(&mut self, power_up_effect: PowerUpEffect, events: &mut PowerUpEvents) {
+        // Multiple balls can hit the same power-up in the same tick.
+        let index = match self
+            .spawned_power_ups
+            .iter()
+            .position(|p| p.id() == power_up_effect.power_up_id)
+        {
+            Some(index) => index,
+            None => return,
+        };
+        self.spawned_power_ups.remove(index);
+
+        let definition = power_up_effect.effect_type.definition();
+        let activating_team = power_up_effect.activating_team;
+        let targets = self.power_up_targets(definition.target, activating_team);
+        let target_teams = TargetTeams {
+            activating_team,
+            mask: targets,
+        };
+        self.active_power_ups.push(ActivePowerUp {
+            time_left: definition.duration,
+            effect: power_up_effect,
+            targets,
+        });
+        self.refresh_power_up_modifiers();
+        debug!(
+            "Handle power-up effect: {:?}, targets {:b}",
+            power_up_effect, targets
+        );
 
-        match power_up_effect.effect_type {
+        let packet = match power_up_effect.effect_type {
             PowerUpEffectType::GrowOwnTeam => {
-                if let Some(rebalance_data) = self
-                    .resize_team_members(power_up_effect.activating_team, POWER_UP_RESIZE_FACTOR)
-                {
-                    PowerUpPacket::ResizePlayers(power_up_effect.activating_team, rebalance_data)
-                } else {
-                    PowerUpPacket::None
-                }
+                self.players.add_team_modifier(
+                    targets,
+                    TeamModifierKind::Width(POWER_UP_RESIZE_FACTOR),
+                    definition.duration,
+                );
+                self.rebalance_teams(activating_team, targets)
             }
-            PowerUpEffectType::BonusPoints => {
-                PowerUpPacket::BonusPoints(power_up_effect.activating_team)
+            PowerUpEffectType::BonusPoints => PowerUpPacket::BonusPoints(activating_team),
+            PowerUpEffectType::SplitRGB => PowerUpPacket::SplitRGB(activating_team),
+            PowerUpEffectType::RotateField => PowerUpPacket::RotateField(activating_team),
+            PowerUpEffectType::SlowDown => {
+                self.players.add_team_modifier(
+                    targets,
+                    TeamModifierKind::Speed(SLOWDOWN_FACTOR),
+                    definition.duration,
+                );
+                PowerUpPacket::SlowDown(target_teams, SLOWDOWN_FACTOR)
             }
-            PowerUpEffectType::SplitRGB => PowerUpPacket::SplitRGB(power_up_effect.activating_team),
-            PowerUpEffectType::RotateField => {
-                PowerUpPacket::RotateField(power_up_effect.activating_team)
+            PowerUpEffectType::Multiball => {
+                let (pos, angle) = self
+                    .tracker
+                    .next_ball_characteristics(self.shared_data.nr_throw_sectors());
+                let ball = Ball::new(pos, angle);
+                let data = ball.characteristics();
+                self.balls.push(ball);
+                PowerUpPacket::Multiball(activating_team, data)
             }
-            PowerUpEffectType::SlowDown => {
-                self.players
-                    .set_team_speed(power_up_effect.activating_team, SLOWDOWN_FACTOR);
-                PowerUpPacket::SlowDown(power_up_effect.activating_team, SLOWDOWN_FACTOR)
+            PowerUpEffectType::ShrinkOpponents => {
+                self.players.add_team_modifier(
+                    targets,
+                    TeamModifierKind::Width(1.0 / POWER_UP_RESIZE_FACTOR),
+                    definition.duration,
+                );
+                self.rebalance_teams(activating_team, targets)
+            }
+            PowerUpEffectType::SpeedUpBalls => {
+                PowerUpPacket::SpeedUpBalls(activating_team, POWER_UP_BALL_SPEED_FACTOR)
+            }
+            PowerUpEffectType::ShieldWall => PowerUpPacket::ShieldWall(target_teams, true),
+            PowerUpEffectType::ReverseControls => {
+                self.players.add_team_modifier(
+                    targets,
+                    TeamModifierKind::InvertedControls,
+                    definition.duration,
+                );
+                PowerUpPacket::ReverseControls(target_teams, true)
+            }
+        };
+        push_power_up_event(events, power_up_effect.power_up_id, packet);
+    }
+
+    /// Handle the end of a power up effect.
+    /// The team modifiers expire on their own at the same time.
+    fn end_power_up(&mut self, active: ActivePowerUp) -> PowerUpPacket {
+        debug!("End power-up effect: {:?}", active.effect);
+        self.refresh_power_up_modifiers();
+
+        let activating_team = active.effect.activating_team;
+        let targets = active.targets;
+        let target_teams = TargetTeams {
+            activating_team,
+            mask: targets,
+        };
+        match active.effect.effect_type {
+            PowerUpEffectType::GrowOwnTeam | PowerUpEffectType::ShrinkOpponents => {
+                self.rebalance_teams(activating_team, targets)
+            }
+            PowerUpEffectType::BonusPoints => PowerUpPacket::None,
+            PowerUpEffectType::SplitRGB => PowerUpPacket::SplitRGB(activating_team),
+            PowerUpEffectType::RotateField => PowerUpPacket::RotateField(activating_team),
+            PowerUpEffectType::SlowDown => PowerUpPacket::SlowDown(target_teams, 1.0),
+            PowerUpEffectType::Multiball => {
+                // Temporary balls are always after the regular balls, so we can remove any of them.
+                self.balls.pop();
+                PowerUpPacket::RemoveBall(self.balls.len() as u8)
+            }
+            PowerUpEffectType::SpeedUpBalls => PowerUpPacket::SpeedUpBalls(activating_team, 1.0),
+            PowerUpEffectType::ShieldWall => PowerUpPacket::ShieldWall(target_teams, false),
+            PowerUpEffectType::ReverseControls => {
+                PowerUpPacket::ReverseControls(target_teams, false)
             }
         }
     }
 
-    /// Power up state machine handling.
-    fn power_up_state_machine(&mut self, delta: f32) -> PowerUpPacket {
-        let (new_state, packet) = match self.power_up_state {
-            PowerUpState::WaitUntilSpawn(time) => {
-                let time = time - delta;
-                if time <= 0.0 {
+    /// Power up timers handling: expires active effects and spawns new power-ups.
+    fn tick_power_ups(&mut self, delta: f32, events: &mut PowerUpEvents) {
+        // Same delta as the active power-ups below, so the team modifiers expire in the same tick.
+        self.players.tick_team_data(delta);
+
+        let mut i = 0;
+        while i < self.active_power_ups.len() {
+            let active = &mut self.active_power_ups[i];
+            active.time_left -= delta;
+            if active.time_left <= 0.0 {
+                let active = self.active_power_ups.remove(i);
+                let packet = self.end_power_up(active);
+                push_power_up_event(events, active.effect.power_up_id, packet);
+            } else {
+                i += 1;
+            }
+        }
+
+        if let Some(mut spawner) = self.power_up_spawner {
+            let one_at_a_time = !self.features.contains(Features::MULTIPLE_POWER_UPS);
+            let busy = if one_at_a_time {
+                !self.spawned_power_ups.is_empty() || !self.active_power_ups.is_empty()
+            } else {
+                self.spawned_power_ups.len() >= MAX_SPAWNED_POWER_UPS
+            };
+            // Older clients show one power-up at a time, its spawn delay starts when the previous
+            // one is over. Otherwise a full field waits until a spot frees up.
+            if !(one_at_a_time && busy) {
+                spawner.time_left -= delta;
+            }
+            if spawner.time_left <= 0.0 && !busy {
+                if let Some(power_up_type) = spawner.next {
+                    let id = self.create_power_up_id();
                     let pos = self.tracker.next_powerup_location();
-                    let power_up_type = self.tracker.next_powerup_type();
-                    debug!("Spawn power up {:?} at {:?}", power_up_type, pos);
-                    let power_up = PowerUp::new(pos, power_up_type);
-                    (
-                        PowerUpState::Spawned(power_up),
-                        PowerUpPacket::SpawnPowerUp(power_up),
-                    )
-                } else {
-                    (PowerUpState::WaitUntilSpawn(time), PowerUpPacket::None)
+                    debug!("Spawn power up {} {:?} at {:?}", id, power_up_type, pos);
+                    let power_up = PowerUp::new(id, pos, power_up_type);
+                    self.spawned_power_ups.push(power_up);
+                    push_power_up_event(events, id, PowerUpPacket::SpawnPowerUp(power_up));
                 }
+                spawner = self.next_power_up_spawner();
             }
-            PowerUpState::WaitUntilItIsOver(
-                time,
-                PowerUpEffect {
-                    effect_type,
-                    activating_team,
-                },
-            ) => {
-                let time = time - delta;
-                if time <= 0.0 {
-                    let packet = match effect_type {
-                        PowerUpEffectType::GrowOwnTeam => self
-                            .rebalance_team(activating_team)
-                            .map(|data| PowerUpPacket::ResizePlayers(activating_team, data))
-                            .unwrap_or(PowerUpPacket::None),
-                        PowerUpEffectType::BonusPoints => PowerUpPacket::None,
-                        PowerUpEffectType::SplitRGB => PowerUpPacket::SplitRGB(activating_team),
-                        PowerUpEffectType::RotateField => {
-                            PowerUpPacket::RotateField(activating_team)
-                        }
-                        PowerUpEffectType::SlowDown => {
-                            self.players.set_team_speed(activating_team, 1.0);
-                            PowerUpPacket::SlowDown(activating_team, 1.0)
-                        }
-                    };
-                    (PowerUpState::default_spawn_wait_state(), packet)
-                } else {
-                    (
-                        PowerUpState::WaitUntilItIsOver(
-                            time,
-                            PowerUpEffect {
-                                effect_type,
-                                activating_team,
-                            },
-                        ),
-                        PowerUpPacket::None,
-                    )
+            self.power_up_spawner = Some(spawner);
+        }
+    }
+
+    /// Logs, and kicks if configured, players that go over the anti-cheat thresholds.
+    /// Bots are not checked as they don't have a client.
+    async fn check_anti_cheat(&mut self) {
+        let anti_cheat = &config().anti_cheat;
+        for (client_id, violation) in self.players.check_anti_cheat(anti_cheat) {
+            if let Some(client) = self.clients.get_mut(&client_id) {
+                println!(
+                    "[{}] cheat {:x}: {} {} {:?}",
+                    Local::now().format("%d-%m %H:%M"),
+                    self.id,
+                    client_id,
+                    client.addr(),
+                    violation
+                );
+                if anti_cheat.kick {
+                    client.kick().await;
                 }
             }
-            otherwise => (otherwise, PowerUpPacket::None),
-        };
-        self.power_up_state = new_state;
-        packet
+        }
+    }
+
+    /// Gets the room data for ball ticks.
+    fn room_data_for_ball<'a>(&self, delta: f32, power_ups: &'a [PowerUp]) -> RoomDataForBall<'a> {
+        RoomDataForBall {
+            delta,
+            team_count: self.shared_data.nr_teams().into(),
+            power_ups,
+            spin_towards_center: self.shared_data.spin_towards_center(),
+            speed_factor: self.ball_speed_factor,
+            shielded_teams: self.shielded_teams,
+        }
     }
 
     /// Creates a new client id.
@@ -521,42 +1081,53 @@ impl Room {
             return;
         }
 
+        match self.pause_state {
+            PauseState::Running => {}
+            PauseState::Paused(_) => return,
+            PauseState::Resuming(paused_since, countdown) => {
+                let countdown = countdown - delta;
+                if countdown > 0.0 {
+                    self.pause_state = PauseState::Resuming(paused_since, countdown);
+                } else {
+                    self.finish_resume(paused_since);
+                    self.broadcast(MessageToClient::Pause(PausePacket::Resumed))
+                        .await;
+                }
+                return;
+            }
+        }
+
         self.seconds_passed_since_start += delta;
 
-        let frame_nr = self.frame_nr();
+        let frame_time = self.frame_time();
+
+        let tick_rate = self.shared_data.tick_rate();
 
         // Ticks bot
-        self.players.tick_bots(&self.bots, &self.balls);
+        self.players.tick_bots(&self.bots, &self.balls, tick_rate);
 
         // Handle late collisions
+        let room_data_for_ball = self.room_data_for_ball(tick_rate.frames_per_tick() as f32, &[]);
         let (client_syncs, mut ball_syncs) = self.players.handle_late_collisions(
             self.frame_timer,
             &mut self.balls,
-            &self.shared_data,
+            room_data_for_ball,
+            tick_rate,
         );
+        self.check_anti_cheat().await;
 
-        let mut power_up_packet = PowerUpPacket::None;
+        let mut power_up_events = PowerUpEvents::new();
         if self.seconds_passed_since_start >= TIME_WAIT_BEFORE_START {
             let end_time = TIME_WAIT_BEFORE_START + self.shared_data.match_time_f32();
 
             if self.seconds_passed_since_start < end_time {
-                power_up_packet = self.power_up_state_machine(delta);
-
-                // Need to read power up from current state.
-                let power_up = match self.power_up_state {
-                    PowerUpState::Spawned(p) => Some(p),
-                    _ => None,
-                };
+                self.tick_power_ups(delta, &mut power_up_events);
 
                 // Convert to "tick time".
                 let delta = delta * 60.0;
 
-                let room_data_for_ball = RoomDataForBall {
-                    delta,
-                    team_count: self.shared_data.nr_teams().into(),
-                    power_up,
-                    spin_towards_center: self.shared_data.spin_towards_center(),
-                };
+                let room_data_for_ball = self.room_data_for_ball(delta, &self.spawned_power_ups);
+                let mut power_up_effects = SmallVec::<[PowerUpEffect; 2]>::new();
 
                 // Game play loop.
                 for i in 0..self.balls.len() {
@@ -569,6 +1140,7 @@ impl Room {
                     match result {
                         BallTickResult::Outside => {
                             //debug!("outside {}", ball.last_hit_team());
+                            self.last_conceded_team = ball.last_hit_pair().receiving_team();
                             ball.reset_characteristics(
                                 self.tracker
                                     .next_ball_characteristics(self.shared_data.nr_throw_sectors()),
@@ -586,9 +1158,13 @@ impl Room {
                     }
 
                     if let Some(power_up_effect) = power_up_effect {
-                        power_up_packet = self.handle_power_up(power_up_effect);
+                        power_up_effects.push(power_up_effect);
                     }
                 }
+
+                for power_up_effect in power_up_effects {
+                    self.handle_power_up(power_up_effect, &mut power_up_events);
+                }
             } else if self.seconds_passed_since_start > end_time + TIME_WAIT_BEFORE_RESET {
                 self.end_match().await;
             }
@@ -597,20 +1173,25 @@ impl Room {
         // Just send everything to aggressively sync.
         //if !client_syncs.is_empty()
         //    || !ball_syncs.is_empty()
-        //    || !matches!(power_up_packet, PowerUpPacket::None)
+        //    || !power_up_events.is_empty()
         {
-            //debug!("power up packet: {:?}", power_up_packet);
+            //debug!("power up events: {:?}", power_up_events);
             let sync = SyncMessage {
-                frame_nr,
+                frame_time,
                 client_syncs,
                 ball_syncs,
-                power_up: power_up_packet,
+                power_ups: power_up_events,
             };
 
-            self.broadcast(MessageToClient::Sync(&sync)).await;
+            self.sync_history.push(Snapshot::capture(
+                self.frame_timer,
+                &self.balls,
+                &self.players,
+            ));
+            self.broadcast_sync(&sync).await;
         }
 
-        self.frame_timer += TPF;
+        self.frame_timer += tick_rate.frames_per_tick();
     }
 
     /// Inbox process.
@@ -624,7 +1205,7 @@ impl Room {
                 sender.send(client).expect("send client");
 
                 if self.client_count() == 0 {
-                    rooms().lock().await.remove(self.id());
+                    rooms().remove(self.id()).await;
                     return false;
                 }
             }
@@ -638,7 +1219,7 @@ impl Room {
                     self.players.count()
                 );
                 if !self.is_started && sender == self.host_client_id && self.players.count() > 1 {
-                    rooms().lock().await.mark_as_playing(self.id());
+                    rooms().mark_as_playing(self.id());
                     self.start().await;
                 }
             }
@@ -653,19 +1234,52 @@ impl Room {
                 }
             }
 
+            MessageToInbox::Pong(id, token, at) => {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    if client.pong(token, at) {
+                        self.players.set_rewind_window(id, client.rewind_window());
+                    }
+                }
+            }
+
+            MessageToInbox::ClockSync(id, client_time, received) => {
+                let received = self.frame_time_at(received);
+                let sent = self.frame_time();
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client
+                        .send(&MessageToClient::ClockSync(ClockSyncReply {
+                            client_time,
+                            received,
+                            sent,
+                        }))
+                        .await;
+                }
+            }
+
             MessageToInbox::MovePlayer(id, update) => {
-                if self.is_started {
-                    self.players.queue_move_for(id, update);
+                if self.is_started && matches!(self.pause_state, PauseState::Running) {
+                    // Inputs are stamped with the synced clock, they can't be ahead of ours.
+                    let now = self.frame_time() / 1000;
+                    if update.seq_nr > now + MAX_FRAMES_AHEAD {
+                        debug!("drop move from the future {} > {}", update.seq_nr, now);
+                    } else {
+                        self.players.queue_move_for(id, update);
+                    }
                 }
             }
 
             MessageToInbox::JoinPlayer(join_tx, mut client, name) => {
-                debug_assert!(!self.is_started);
+                // The match may have started after the player found the room.
+                if self.is_started || !self.shared_data.has_free_slots() {
+                    let _ = join_tx.send(Err(client));
+                    return true;
+                }
 
                 let id = self.create_client_id();
 
-                // If the player is not the creator of the room.
-                if id > 0 {
+                // If the player is not the creator of the room. Nobody creates a quick-play room,
+                // so everyone gets the room state.
+                if id > 0 || self.shared_data.is_quick_play() {
                     let already_joined = self
                         .players
                         .iter_mut()
@@ -688,12 +1302,21 @@ impl Room {
                 }
 
                 self.add_player(id, name).await;
+                self.players.set_rewind_window(id, client.rewind_window());
                 self.clients.insert(id, client);
-                join_tx.send(JoinData { id }).expect("join data");
+                let (closed_tx, closed) = oneshot::channel();
+                self.closed_txs.insert(id, closed_tx);
+                join_tx
+                    .send(Ok(JoinData { id, closed }))
+                    .expect("join data");
             }
 
             MessageToInbox::AddBot(sender) => {
-                if !self.is_started && sender == self.host_client_id && self.bots.len() < 10 {
+                if !self.is_started
+                    && sender == self.host_client_id
+                    && self.bots.len() < MAX_BOTS
+                    && self.shared_data.player_count() < MAX_PLAYERS
+                {
                     self.add_bot(format!("Bot {}", self.bots.len() + 1)).await;
                 }
             }
@@ -703,15 +1326,61 @@ impl Room {
                     self.remove_last_bot().await;
                 }
             }
+
+            MessageToInbox::Pause(sender) => {
+                self.pause(sender).await;
+            }
+
+            MessageToInbox::Resume(sender) => {
+                self.resume(sender).await;
+            }
+
+            MessageToInbox::AckSync(sender, frame) => {
+                self.sync_history.ack(sender, frame);
+            }
         }
 
         true
     }
+
+    /// Tears down a crashed room and sends its clients back to the lobby.
+    async fn tear_down(&mut self) {
+        println!(
+            "[{}] crash {:x}: {}",
+            Local::now().format("%d-%m %H:%M"),
+            self.id,
+            self.clients.len()
+        );
+
+        {
+            let rooms = rooms();
+            rooms.remove(self.id).await;
+            if let Some(host) = self
+                .clients
+                .get(&self.host_client_id)
+                .filter(|_| !self.shared_data.is_quick_play())
+            {
+                rooms.owner_leave(host.addr());
+            }
+        }
+
+        for (id, mut client) in self.clients.drain() {
+            if client.version().supports(Features::ROOM_CLOSED) {
+                client.send(&MessageToClient::RoomClosed).await;
+            } else {
+                // Older clients can't go back to the lobby, reconnecting is all they can do.
+                client.kick().await;
+            }
+            if let Some(closed_tx) = self.closed_txs.remove(&id) {
+                let _ = closed_tx.send(client);
+            }
+        }
+    }
 }
 
 /// Room async loop.
 pub async fn room_loop(
-    mut inbox_rx: UnboundedReceiver<MessageToInbox>,
+    mut inbox_rx: Receiver<MessageToInbox>,
     id: RoomId,
     shared_data: Arc<SharedRoomData>,
 ) {
@@ -720,18 +1389,325 @@ pub async fn room_loop(
     // We don't need to wait for the first message, because this loop will only exit if
     // `inbox_process` becomes false.
     loop {
-        tokio::select! {
-            deadline = room.tick_delay.tick() => {
-                //let now = std::time::Instant::now();
+        // A panic only takes down this room: the state may be inconsistent, but the clients are
+        // still intact and can be sent back to the lobby.
+        let result = AssertUnwindSafe(async {
+            tokio::select! {
+                deadline = room.tick_delay.tick() => {
+                    //let now = std::time::Instant::now();
+                    room.tick(deadline).await;
+                    //println!("{:?}", std::time::Instant::now()-now);
+                    room.match_quick_play(deadline).await
+                }
+
+                Some(msg) = inbox_rx.next() => {
+                    room.inbox_process(msg).await
+                }
+
+                _ = room.ping_delay.tick() => {
+                    room.ping_clients().await;
+                    true
+                }
+            }
+        })
+        .catch_unwind()
+        .await;
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(_) => {
+                room.tear_down().await;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ClientMoveUpdate, RoomMessageFromClient};
+    use crate::stream::PeerAddr;
+    use crate::validation::Validate;
+    use crate::version::with_version;
+    use futures::channel::mpsc;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    async fn quick_play_rooms_fill_up_with_bots_and_start() {
+        let shared_data = Arc::new(SharedRoomData::new_quick_play("quick".to_owned()));
+        let mut room = Room::new(0, shared_data);
+        let start = room.quick_play_start.expect("quick-play room");
+        room.add_player(0, "a".to_owned()).await;
+        room.add_player(1, "b".to_owned()).await;
+
+        assert!(
+            room.match_quick_play(start - Duration::from_millis(1))
+                .await
+        );
+        assert!(!room.is_started);
+        assert!(room.match_quick_play(start).await);
+        assert!(room.is_started);
+        assert_eq!(room.players.count(), QUICK_PLAY_PLAYERS as usize);
+
+        // Nobody showed up.
+        let shared_data = Arc::new(SharedRoomData::new_quick_play("quick".to_owned()));
+        let mut room = Room::new(0, shared_data);
+        let start = room.quick_play_start.expect("quick-play room");
+        assert!(!room.match_quick_play(start).await);
+    }
+
+    /// Feeds random messages that pass validation to a running room.
+    #[tokio::test]
+    async fn fuzz_room_messages() {
+        let shared_data = Arc::new(SharedRoomData::new("fuzz".to_owned()));
+        let mut room = Room::new(0, shared_data);
+        room.add_player(0, "a".to_owned()).await;
+        room.add_player(1, "b".to_owned()).await;
+        room.start().await;
+
+        let mut rng = SmallRng::seed_from_u64(0x5eed);
+        let mut deadline = room.last_tick_time;
+        let mut buf = Vec::new();
+
+        for i in 0..5000 {
+            buf.clear();
+            buf.push(rng.gen_range(0..8));
+            let len = rng.gen_range(0..24);
+            buf.extend((0..len).map(|_| rng.gen::<u8>()));
+
+            let id = rng.gen_range(0..2);
+            match crate::bincode::deserialize::<RoomMessageFromClient>(&buf) {
+                Ok(msg) if msg.validate().is_ok() => match msg {
+                    RoomMessageFromClient::Move(update) => {
+                        room.inbox_process(MessageToInbox::MovePlayer(id, update))
+                            .await;
+                    }
+                    RoomMessageFromClient::Pause => {
+                        room.inbox_process(MessageToInbox::Pause(id)).await;
+                    }
+                    RoomMessageFromClient::Resume => {
+                        room.inbox_process(MessageToInbox::Resume(id)).await;
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+
+            // Stay within the match, ending it touches the global room manager.
+            if i % 4 == 0 {
+                deadline += room.shared_data.tick_rate().tick_time();
+                room.tick(deadline).await;
+            }
+        }
+    }
+
+    /// Creates a started room with three connected players, the first one is the host.
+    async fn room_with_three_clients() -> (Room, Vec<mpsc::UnboundedReceiver<Message>>) {
+        let shared_data = Arc::new(SharedRoomData::new("pause".to_owned()));
+        let mut room = Room::new(0, shared_data);
+        let mut rxs = Vec::new();
+        for id in 0..3 {
+            let (tx, rx) = mpsc::unbounded();
+            rxs.push(rx);
+            room.add_player(id, id.to_string()).await;
+            room.clients.insert(id, Client::new(tx, PeerAddr::Unix));
+        }
+        room.start().await;
+        (room, rxs)
+    }
+
+    #[tokio::test]
+    async fn pausing_needs_the_host_or_a_majority() {
+        let (mut room, _rxs) = room_with_three_clients().await;
+
+        room.pause(1).await;
+        assert!(matches!(room.pause_state, PauseState::Running));
+        room.pause(2).await;
+        assert!(matches!(room.pause_state, PauseState::Paused(_)));
+        assert!(room.pause_votes.is_empty());
+
+        // One vote isn't enough to resume either, but the host doesn't need a vote.
+        room.resume(1).await;
+        assert!(matches!(room.pause_state, PauseState::Paused(_)));
+        room.resume(0).await;
+        assert!(matches!(room.pause_state, PauseState::Resuming(..)));
+    }
+
+    #[tokio::test]
+    async fn pause_votes_expire() {
+        let (mut room, _rxs) = room_with_three_clients().await;
+        let now = Instant::now();
+
+        assert!(!room.pause_vote(1, now));
+        assert!(!room.pause_vote(2, now + PAUSE_VOTE_TIME));
+        assert!(room.pause_vote(1, now + PAUSE_VOTE_TIME));
+        assert!(room.pause_vote(0, now + PAUSE_VOTE_TIME * 3));
+    }
+
+    #[tokio::test]
+    async fn resuming_skips_the_paused_time() {
+        let (mut room, _rxs) = room_with_three_clients().await;
+        let timer = room.timer;
+        let paused = Duration::from_secs(2);
+
+        room.finish_resume(Instant::now() - paused);
+        let shift = room.timer - timer;
+        assert!(shift >= paused && shift < paused + Duration::from_secs(1));
+        assert!(matches!(room.pause_state, PauseState::Running));
+    }
+
+    /// Late hits are accepted for the rewind window of the client at every tick rate,
+    /// up to the latency compensation window.
+    #[test]
+    fn history_covers_compensation_window() {
+        for tick_rate in TickRate::ALL {
+            let history_len = tick_rate.history_len();
+            let frames_per_tick = tick_rate.frames_per_tick();
+            assert!(history_len <= MOVEMENT_BUFFER_CAP);
+            let covered_frames = (history_len as u32 - 1) * frames_per_tick;
+            assert!(Duration::from_secs(covered_frames.into()) / 60 >= LATENCY_COMPENSATION_WINDOW);
+
+            // A move made during the frames of the current tick, and the oldest accepted moves.
+            let frame_time = 600;
+            let newest = frame_time - (frames_per_tick - 1);
+            for window in [Duration::from_millis(250), LATENCY_COMPENSATION_WINDOW * 2] {
+                let max_offset = tick_rate.ticks_in(window.min(LATENCY_COMPENSATION_WINDOW));
+                assert_eq!(
+                    tick_rate.history_offset(frame_time, newest, window),
+                    Some(0)
+                );
+                let oldest = newest - max_offset * frames_per_tick;
+                assert_eq!(
+                    tick_rate.history_offset(frame_time, oldest, window),
+                    Some(max_offset)
+                );
+                assert_eq!(
+                    tick_rate.history_offset(frame_time, oldest - frames_per_tick, window),
+                    None
+                );
+            }
+        }
+    }
+
+    /// Moves stamped ahead of the match clock are dropped.
+    #[tokio::test]
+    async fn rejects_moves_from_the_future() {
+        let shared_data = Arc::new(SharedRoomData::new("clock".to_owned()));
+        let mut room = Room::new(0, shared_data);
+        room.add_player(0, "a".to_owned()).await;
+        room.add_player(1, "b".to_owned()).await;
+        room.start().await;
+
+        let move_update = |seq_nr| ClientMoveUpdate {
+            delta: 0.0,
+            seq_nr,
+            ball_hit: NO_TEAM,
+            spin: 0.0,
+        };
+        let seq_nr = |room: &Room| {
+            let (_, player) = room.players.iter().find(|&(&id, _)| id == 0).unwrap();
+            let seq_nr = player.borrow().move_seq_nr();
+            seq_nr
+        };
+        room.inbox_process(MessageToInbox::MovePlayer(0, move_update(60 * 60)))
+            .await;
+        assert_eq!(seq_nr(&room), 0);
+        room.inbox_process(MessageToInbox::MovePlayer(0, move_update(MAX_FRAMES_AHEAD)))
+            .await;
+        assert_eq!(seq_nr(&room), MAX_FRAMES_AHEAD);
+    }
+
+    /// Matches with bots advance 60 frames per second at every tick rate.
+    #[tokio::test]
+    async fn matches_run_at_every_tick_rate() {
+        for tick_rate in TickRate::ALL {
+            let shared_data = Arc::new(SharedRoomData::new("rate".to_owned()));
+            let mut settings = shared_data.settings();
+            settings.tick_rate = tick_rate;
+            assert!(shared_data.update_settings(settings));
+            let mut room = Room::new(0, shared_data);
+            room.add_player(0, "a".to_owned()).await;
+            room.add_bot("bot".to_owned()).await;
+            room.start().await;
+
+            let mut deadline = room.last_tick_time;
+            let ticks = tick_rate.ticks_per_sec() * 2;
+            for _ in 0..ticks {
+                deadline += tick_rate.tick_time();
                 room.tick(deadline).await;
-                //println!("{:?}", std::time::Instant::now()-now);
             }
+            assert_eq!(room.frame_timer, 120);
+            assert!((room.seconds_passed_since_start - 2.0).abs() < 1e-3);
+
+            // The first bot move has sequence number zero, which doesn't come after the initial
+            // one and is dropped. Every later move got accepted.
+            let bot = room.bots[0].id();
+            let (_, player) = room.players.iter().find(|&(&id, _)| id == bot).unwrap();
+            let mut player = player.borrow_mut();
+            let frames_per_tick = tick_rate.frames_per_tick();
+            assert_eq!(player.move_seq_nr(), (ticks - 1) * frames_per_tick);
+            assert_eq!(player.stats_mut().moves(), ticks - 1);
+        }
+    }
 
-            Some(msg) = inbox_rx.next() => {
-                if !room.inbox_process(msg).await {
-                    break;
-                }
+    /// Compares the size of full syncs and delta syncs with 8 balls.
+    #[tokio::test]
+    async fn delta_sync_saves_bandwidth() {
+        let shared_data = Arc::new(SharedRoomData::new("delta".to_owned()));
+        let mut settings = shared_data.settings();
+        settings.balls = 8;
+        settings.power_ups = false;
+        assert!(shared_data.update_settings(settings));
+        let mut room = Room::new(0, shared_data);
+        room.add_player(0, "a".to_owned()).await;
+        room.add_player(1, "b".to_owned()).await;
+        room.start().await;
+
+        let mut deadline = room.last_tick_time;
+        let (mut full_size, mut delta_size) = (0, 0);
+        let mut last_frame = None;
+        // Skip the wait before the start, balls don't move yet.
+        for i in 0..200 {
+            deadline += room.shared_data.tick_rate().tick_time();
+            room.tick(deadline).await;
+            let current = room.sync_history.latest().unwrap();
+
+            let sync = SyncMessage {
+                frame_time: room.frame_time(),
+                client_syncs: Vec::new(),
+                ball_syncs: room
+                    .balls
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ball)| BallSync::new(i as _, 0, ball))
+                    .collect(),
+                power_ups: SmallVec::new(),
+            };
+            let base = last_frame.and_then(|_| room.sync_history.base_for(0));
+            let delta = DeltaSyncMessage::new(&sync, base, current);
+            // Without the compact ball data that came after delta syncs.
+            let encode = |msg: &MessageToClient| {
+                let bytes = with_version(ProtocolVersion::negotiate(15).unwrap(), || {
+                    crate::bincode::serialize(msg).unwrap()
+                });
+                bytes.len()
+            };
+            if i >= 100 {
+                full_size += encode(&MessageToClient::Sync(&sync));
+                delta_size += encode(&MessageToClient::DeltaSync(&delta));
             }
+
+            // The client acknowledges every frame.
+            last_frame = Some(current.frame());
+            room.sync_history.ack(0, current.frame());
         }
+
+        // 8 balls take 192 bytes per tick in full, and about 80 as deltas.
+        assert_eq!(full_size, 100 * 192);
+        assert!(delta_size <= 100 * 85, "{} bytes in deltas", delta_size);
     }
 }
@@ -1,55 +1,232 @@
-use crate::protocol::MessageToInbox;
+use crate::config::config;
+use crate::directory::RoomDirectory;
+use crate::protocol::{MessageToInbox, RoomListUpdate, RoomQuery, RoomSort};
 use crate::room::room_loop;
 use crate::shared_room_data::SharedRoomData;
+use crate::stream::PeerAddr;
+use arc_swap::ArcSwap;
+use atomic::Atomic;
 use chrono::Local;
-use futures::channel::mpsc::{self, UnboundedSender};
-use std::collections::HashMap;
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use fnv::FnvBuildHasher;
+use futures::channel::mpsc::{self, Sender};
+use rand::Rng;
 use std::mem::swap;
-use std::net::IpAddr;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 pub type RoomId = u64;
 
 const BITCNT: u64 = 40;
 const BITMASK: u64 = (1 << (BITCNT / 2)) - 1;
+const CODE_MASK: u64 = (1 << BITCNT) - 1;
 const ROUNDS: u64 = 10;
 const ALPHABET: [u8; 32] = *b"T48W1GVJF37AYEB256IPMS90ZDHRKLXQ";
 
 const MAX_ROOM_CREATIONS_PER_IP: u32 = 12;
 
+/// Codes tried before giving up when other nodes took them.
+const MAX_CODE_CLAIMS: u32 = 8;
+
+/// How long a room list may be reused, it misses player count and settings changes.
+/// Subscribed clients get updates at this interval.
+pub const LIST_CACHE_TTL: Duration = Duration::from_secs(1);
+
+/// Rooms on a page of a room query.
+pub const ROOMS_PER_PAGE: usize = 20;
+
+/// Name of the rooms made by the matchmaker.
+const QUICK_PLAY_NAME: &str = "Quick play";
+
+/// Rooms sent to clients that can't query, so the list fits in a message.
+pub const MAX_LISTED_ROOMS: usize = 100;
+
+/// Lifecycle of a room. Only the room itself changes its state.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u8)]
+pub enum RoomState {
+    /// In the lobby list, players can join.
+    Waiting,
+    /// A match is running, players can't join.
+    Playing,
+}
+
+const_assert!(Atomic::<RoomState>::is_lock_free());
+
 pub struct RoomData {
-    sender: UnboundedSender<MessageToInbox>,
+    sender: Sender<MessageToInbox>,
     shared_data: Arc<SharedRoomData>,
+    state: Atomic<RoomState>,
 }
 
+/// Snapshot of the waiting rooms, shared between all players that list them.
+pub struct RoomList {
+    pub rooms: Vec<(String, SharedRoomData)>,
+    built: Instant,
+}
+
+impl RoomList {
+    /// Finds the rooms on a page of the query, and the number of matching rooms.
+    pub fn query(&self, query: &RoomQuery) -> (u32, Vec<&(String, SharedRoomData)>) {
+        let filter = &query.filter;
+        let name = filter.name.to_ascii_lowercase();
+        let mut rooms = self
+            .rooms
+            .iter()
+            .filter(|(_, data)| {
+                (!filter.free_slots || data.has_free_slots())
+                    && filter.power_ups.is_none_or(|p| p == data.power_ups())
+                    && filter.balls.is_none_or(|b| b == data.nr_balls())
+                    && filter.match_time.is_none_or(|t| t == data.match_time())
+                    && data.name().to_ascii_lowercase().contains(&name)
+            })
+            .collect::<Vec<_>>();
+
+        // Ties are broken by code, so pages don't overlap.
+        match query.sort {
+            RoomSort::Players => rooms.sort_unstable_by(|(a_code, a), (b_code, b)| {
+                (b.player_count(), a_code).cmp(&(a.player_count(), b_code))
+            }),
+            RoomSort::FewestPlayers => {
+                rooms.sort_unstable_by_key(|(code, data)| (data.player_count(), code))
+            }
+            RoomSort::Name => {
+                rooms.sort_unstable_by_key(|(code, data)| (data.name().to_ascii_lowercase(), code))
+            }
+        }
+
+        let matching = rooms.len() as u32;
+        let start = (query.page as usize * ROOMS_PER_PAGE).min(rooms.len());
+        rooms.truncate(start + ROOMS_PER_PAGE);
+        rooms.drain(..start);
+        (matching, rooms)
+    }
+}
+
+/// A page of the room list that a client subscribed to.
+pub struct RoomSubscription {
+    pub query: RoomQuery,
+    /// The page as the client has it.
+    sent: Vec<(String, SharedRoomData)>,
+    matching: u32,
+    playing: usize,
+}
+
+impl RoomSubscription {
+    /// Subscribes to a query, the client already got this page.
+    pub fn new(
+        query: RoomQuery,
+        page: &[&(String, SharedRoomData)],
+        matching: u32,
+        playing: usize,
+    ) -> Self {
+        Self {
+            query,
+            sent: page.iter().map(|&room| room.clone()).collect(),
+            matching,
+            playing,
+        }
+    }
+
+    /// Finds the changes to the page, if there are any, and remembers them as sent.
+    pub fn update<'a>(&mut self, list: &'a RoomList, playing: usize) -> Option<RoomListUpdate<'a>> {
+        let (matching, page) = list.query(&self.query);
+        let changed = page
+            .iter()
+            .copied()
+            .filter(|(code, data)| {
+                !self
+                    .sent
+                    .iter()
+                    .any(|(sent_code, sent)| sent_code == code && sent.listing_eq(data))
+            })
+            .collect::<Vec<_>>();
+        let removed = self
+            .sent
+            .iter()
+            .filter(|(sent_code, _)| page.iter().all(|(code, _)| code != sent_code))
+            .map(|(code, _)| code.clone())
+            .collect::<Vec<_>>();
+        if changed.is_empty()
+            && removed.is_empty()
+            && matching == self.matching
+            && playing == self.playing
+        {
+            return None;
+        }
+
+        *self = Self::new(self.query.clone(), &page, matching, playing);
+        Some(RoomListUpdate {
+            matching,
+            playing,
+            changed,
+            removed,
+        })
+    }
+}
+
+/// Registry of all rooms, safe to use from any thread without a global lock.
+/// The maps are sharded, the counters are atomic and the room list is a cached snapshot.
 pub struct RoomManager {
-    rooms: HashMap<RoomId, RoomData>,
-    playing_rooms: HashMap<RoomId, RoomData>,
-    ip_count: HashMap<IpAddr, u32>,
-    next_room_counter: RoomId,
-    xor_thing: u64,
+    rooms: DashMap<RoomId, RoomData, FnvBuildHasher>,
+    addr_count: DashMap<PeerAddr, u32, FnvBuildHasher>,
+    waiting_count: AtomicUsize,
+    playing_count: AtomicUsize,
+    /// Starts at a random code so that nodes sharing a directory don't try the same codes.
+    next_room_counter: AtomicU64,
+    list_cache: ArcSwap<RoomList>,
+    /// Rooms were added, removed or changed state since the list was built.
+    list_dirty: AtomicBool,
+    list_rebuilding: AtomicBool,
+    /// Room codes of all nodes, so players can join a room on another node.
+    directory: Arc<dyn RoomDirectory>,
+    node_address: String,
 }
 
 pub enum RoomSpawnFailReason {
     /// We limit the amount of rooms that can be created from an IP address to partially prevent DoS.
     TooManyFromSameIp,
+    /// No room code could be claimed in the directory.
+    Directory,
+}
+
+/// Where a room that a player wants to join is.
+pub enum RoomLocation {
+    /// On this node and waiting for players.
+    Local(Sender<MessageToInbox>),
+    /// On the node with this address.
+    Remote(String),
+    /// Doesn't exist or can't be joined.
+    Missing,
 }
 
 impl RoomManager {
-    /// Creates a new RoomManager.
+    /// Creates a new RoomManager with the configured directory.
     pub fn new() -> Self {
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards");
+        let config = &config().directory;
+        Self::with_directory(config.open(), config.node_address.clone())
+    }
 
+    /// Creates a new RoomManager for the node with the given address.
+    pub fn with_directory(directory: Arc<dyn RoomDirectory>, node_address: String) -> Self {
         Self {
             rooms: Default::default(),
-            playing_rooms: Default::default(),
-            ip_count: Default::default(),
-            next_room_counter: 0,
-            xor_thing: time.as_secs(),
+            addr_count: Default::default(),
+            waiting_count: AtomicUsize::new(0),
+            playing_count: AtomicUsize::new(0),
+            next_room_counter: AtomicU64::new(rand::thread_rng().gen_range(0..=CODE_MASK)),
+            list_cache: ArcSwap::from_pointee(RoomList {
+                rooms: Vec::new(),
+                built: Instant::now(),
+            }),
+            list_dirty: AtomicBool::new(false),
+            list_rebuilding: AtomicBool::new(false),
+            directory,
+            node_address,
         }
     }
 
@@ -59,8 +236,8 @@ impl RoomManager {
     }
 
     /// Transform counter to room id using Feistel cipher.
-    fn crypt(&self, nr: u64) -> u64 {
-        let nr = nr ^ self.xor_thing;
+    fn crypt(nr: u64) -> u64 {
+        let nr = nr & CODE_MASK;
         let mut left = nr >> (BITCNT / 2);
         let mut right = nr & BITMASK;
         for i in 0..ROUNDS {
@@ -94,91 +271,587 @@ impl RoomManager {
         Some(nr)
     }
 
-    /// Increase count for an IP.
-    pub fn increase_count(&mut self, ip: IpAddr, force: bool) -> Result<(), RoomSpawnFailReason> {
-        if let Some(count) = self.ip_count.get_mut(&ip) {
-            if !force && *count >= MAX_ROOM_CREATIONS_PER_IP {
-                return Err(RoomSpawnFailReason::TooManyFromSameIp);
-            }
-            *count += 1;
-        } else {
-            self.ip_count.insert(ip, 1);
+    /// Increase count for an address.
+    pub fn increase_count(&self, addr: PeerAddr, force: bool) -> Result<(), RoomSpawnFailReason> {
+        let mut count = self.addr_count.entry(addr).or_insert(0);
+        if !force && *count >= MAX_ROOM_CREATIONS_PER_IP {
+            return Err(RoomSpawnFailReason::TooManyFromSameIp);
         }
+        *count += 1;
         Ok(())
     }
 
     /// Spawns a new room.
-    pub fn spawn(
-        &mut self,
-        creator: IpAddr,
+    pub async fn spawn(
+        &self,
+        creator: PeerAddr,
         name: String,
-    ) -> Result<(String, UnboundedSender<MessageToInbox>), RoomSpawnFailReason> {
+    ) -> Result<(String, Sender<MessageToInbox>), RoomSpawnFailReason> {
         self.increase_count(creator, false)?;
-        let id = self.crypt(self.next_room_counter);
-        self.next_room_counter += 1;
-        let (inbox_tx, inbox_rx) = mpsc::unbounded::<MessageToInbox>();
-        let shared_data = Arc::new(SharedRoomData::new(name));
+        match self.spawn_room(SharedRoomData::new(name)).await {
+            Some(room) => Ok(room),
+            None => {
+                self.owner_leave(creator);
+                Err(RoomSpawnFailReason::Directory)
+            }
+        }
+    }
+
+    /// Spawns a quick-play room, it has no owner that is limited in rooms.
+    pub async fn spawn_quick_play(&self) -> Option<(String, Sender<MessageToInbox>)> {
+        self.spawn_room(SharedRoomData::new_quick_play(QUICK_PLAY_NAME.to_owned()))
+            .await
+    }
+
+    /// Claims a code and starts the room.
+    async fn spawn_room(
+        &self,
+        shared_data: SharedRoomData,
+    ) -> Option<(String, Sender<MessageToInbox>)> {
+        let id = self.claim_code().await?;
+        let (inbox_tx, inbox_rx) =
+            mpsc::channel::<MessageToInbox>(config().rate_limit.inbox_capacity);
+        let shared_data = Arc::new(shared_data);
         tokio::task::spawn(room_loop(inbox_rx, id, shared_data.clone()));
+        self.register(id, inbox_tx.clone(), shared_data);
+        Some((Self::code_to_str(id), inbox_tx))
+    }
+
+    /// Claims the next free room code in the directory.
+    async fn claim_code(&self) -> Option<RoomId> {
+        for _ in 0..MAX_CODE_CLAIMS {
+            let id = Self::crypt(self.next_room_counter.fetch_add(1, Ordering::Relaxed));
+            match self
+                .directory
+                .claim(&Self::code_to_str(id), &self.node_address)
+                .await
+            {
+                Ok(true) => return Some(id),
+                Ok(false) => {}
+                Err(e) => {
+                    println!(
+                        "[{}] directory claim failed: {}",
+                        Local::now().format("%d-%m %H:%M"),
+                        e
+                    );
+                    return None;
+                }
+            }
+        }
+        None
+    }
+
+    /// Adds a room that is waiting for players.
+    fn register(
+        &self,
+        id: RoomId,
+        sender: Sender<MessageToInbox>,
+        shared_data: Arc<SharedRoomData>,
+    ) {
         self.rooms.insert(
             id,
             RoomData {
-                sender: inbox_tx.clone(),
+                sender,
                 shared_data,
+                state: Atomic::new(RoomState::Waiting),
             },
         );
-        Ok((Self::code_to_str(id), inbox_tx))
+        self.waiting_count.fetch_add(1, Ordering::Relaxed);
+        self.list_dirty.store(true, Ordering::Release);
     }
 
-    /// List rooms.
-    pub async fn list(&self) -> Vec<(String, SharedRoomData)> {
-        let mut v = Vec::with_capacity(self.rooms.len());
-        for (&id, data) in self.rooms.iter() {
-            v.push((Self::code_to_str(id), data.shared_data.deref().clone()));
+    /// Lists the waiting rooms.
+    /// The list is rebuilt by one caller at a time, the others get the previous list meanwhile.
+    pub fn list(&self) -> Arc<RoomList> {
+        let cached = self.list_cache.load_full();
+        let stale =
+            self.list_dirty.load(Ordering::Acquire) || cached.built.elapsed() >= LIST_CACHE_TTL;
+        if stale && !self.list_rebuilding.swap(true, Ordering::Acquire) {
+            self.list_dirty.store(false, Ordering::Release);
+            let list = Arc::new(self.build_list());
+            self.list_cache.store(list.clone());
+            self.list_rebuilding.store(false, Ordering::Release);
+            return list;
         }
-        v
+        cached
     }
 
-    /// Gets the transmit channel for a room.
-    pub fn get_tx(&self, room_id: &str) -> Option<UnboundedSender<MessageToInbox>> {
-        Self::str_to_code(room_id)
-            .and_then(|id| self.rooms.get(&id).map(|data| data.sender.clone()))
+    /// Builds a new list of the waiting rooms.
+    fn build_list(&self) -> RoomList {
+        let mut rooms = Vec::with_capacity(self.room_count());
+        for entry in self.rooms.iter() {
+            if entry.state.load(Ordering::Acquire) == RoomState::Waiting {
+                rooms.push((
+                    Self::code_to_str(*entry.key()),
+                    entry.shared_data.deref().clone(),
+                ));
+            }
+        }
+        RoomList {
+            rooms,
+            built: Instant::now(),
+        }
+    }
+
+    /// Gets the transmit channel for a room that players can join.
+    pub fn get_tx(&self, room_id: &str) -> Option<Sender<MessageToInbox>> {
+        let data = self.rooms.get(&Self::str_to_code(room_id)?)?;
+        (data.state.load(Ordering::Acquire) == RoomState::Waiting
+            && data.shared_data.has_free_slots())
+        .then(|| data.sender.clone())
+    }
+
+    /// Finds the fullest waiting room with space for a quick-play player.
+    /// Quick-play rooms come first because they start by themselves,
+    /// other rooms only count once their host is in them.
+    pub fn find_quick_play_room(&self) -> Option<Sender<MessageToInbox>> {
+        self.rooms
+            .iter()
+            .filter(|data| {
+                let shared_data = &data.shared_data;
+                (shared_data.is_quick_play() || shared_data.player_count() > 0)
+                    && shared_data.has_free_slots()
+                    && data.state.load(Ordering::Acquire) == RoomState::Waiting
+            })
+            .max_by_key(|data| {
+                (
+                    data.shared_data.is_quick_play(),
+                    data.shared_data.player_count(),
+                )
+            })
+            .map(|data| data.sender.clone())
+    }
+
+    /// Finds a room by code, on this node or another one.
+    pub async fn locate(&self, room_id: &str) -> RoomLocation {
+        if let Some(tx) = self.get_tx(room_id) {
+            return RoomLocation::Local(tx);
+        }
+
+        // Only well-formed codes are looked up, they double as file names.
+        let id = match Self::str_to_code(room_id) {
+            Some(id) if room_id.len() == (BITCNT / 5) as usize => id,
+            _ => return RoomLocation::Missing,
+        };
+        match self.directory.lookup(&Self::code_to_str(id)).await {
+            Ok(Some(node)) if node != self.node_address => RoomLocation::Remote(node),
+            Ok(_) => RoomLocation::Missing,
+            Err(e) => {
+                println!(
+                    "[{}] directory lookup failed: {}",
+                    Local::now().format("%d-%m %H:%M"),
+                    e
+                );
+                RoomLocation::Missing
+            }
+        }
+    }
+
+    /// Moves a room from one state to another, None if it wasn't in the `from` state.
+    fn transition(
+        &self,
+        id: RoomId,
+        from: RoomState,
+        to: RoomState,
+    ) -> Option<Ref<'_, RoomId, RoomData>> {
+        let room_data = self.rooms.get(&id)?;
+        room_data
+            .state
+            .compare_exchange(from, to, Ordering::AcqRel, Ordering::Acquire)
+            .ok()?;
+        let (from_count, to_count) = match to {
+            RoomState::Playing => (&self.waiting_count, &self.playing_count),
+            RoomState::Waiting => (&self.playing_count, &self.waiting_count),
+        };
+        from_count.fetch_sub(1, Ordering::Relaxed);
+        to_count.fetch_add(1, Ordering::Relaxed);
+        self.list_dirty.store(true, Ordering::Release);
+        Some(room_data)
     }
 
     /// Mark a room as playing.
-    pub fn mark_as_playing(&mut self, id: RoomId) {
-        if let Some(room_data) = self.rooms.remove(&id) {
-            println!("[{}] play {:x}: {} {:?}", Local::now().format("%d-%m %H:%M"), id, room_data.shared_data.player_count(), room_data.shared_data.match_time());
-            self.playing_rooms.insert(id, room_data);
+    pub fn mark_as_playing(&self, id: RoomId) {
+        if let Some(room_data) = self.transition(id, RoomState::Waiting, RoomState::Playing) {
+            println!(
+                "[{}] play {:x}: {} {:?}",
+                Local::now().format("%d-%m %H:%M"),
+                id,
+                room_data.shared_data.player_count(),
+                room_data.shared_data.match_time()
+            );
         }
     }
 
     /// Unmark a room as playing.
-    pub fn unmark_as_playing(&mut self, id: RoomId) {
-        if let Some(room_data) = self.playing_rooms.remove(&id) {
-            self.rooms.insert(id, room_data);
-        }
+    pub fn unmark_as_playing(&self, id: RoomId) {
+        self.transition(id, RoomState::Playing, RoomState::Waiting);
     }
 
-    /// Removes a room.
-    pub fn remove(&mut self, id: RoomId) {
-        if self.rooms.remove(&id).is_none() {
-            self.playing_rooms.remove(&id);
+    /// Removes a room and releases its code.
+    pub async fn remove(&self, id: RoomId) {
+        if let Some((_, room_data)) = self.rooms.remove(&id) {
+            match room_data.state.load(Ordering::Acquire) {
+                RoomState::Waiting => self.waiting_count.fetch_sub(1, Ordering::Relaxed),
+                RoomState::Playing => self.playing_count.fetch_sub(1, Ordering::Relaxed),
+            };
+            self.list_dirty.store(true, Ordering::Release);
+
+            if let Err(e) = self.directory.release(&Self::code_to_str(id)).await {
+                println!(
+                    "[{}] directory release failed: {}",
+                    Local::now().format("%d-%m %H:%M"),
+                    e
+                );
+            }
         }
     }
 
-    /// Owner leaves a room, update IP counts.
-    pub fn owner_leave(&mut self, ip: IpAddr) {
-        if let Some(count) = self.ip_count.get_mut(&ip) {
-            *count -= 1;
-            if *count == 0 {
-                self.ip_count.remove(&ip);
+    /// Owner leaves a room, update address counts.
+    pub fn owner_leave(&self, addr: PeerAddr) {
+        if let Entry::Occupied(mut count) = self.addr_count.entry(addr) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
             }
         }
     }
 
+    /// Gets the number of rooms that are not playing.
+    #[inline]
+    pub fn room_count(&self) -> usize {
+        self.waiting_count.load(Ordering::Relaxed)
+    }
+
     /// Gets the number of playing rooms.
     #[inline]
     pub fn playing_rooms(&self) -> usize {
-        self.playing_rooms.len()
+        self.playing_count.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::MemoryDirectory;
+    use crate::room::MAX_PLAYERS;
+    use futures::executor::block_on;
+    use futures::lock::Mutex;
+    use std::collections::HashMap;
+    use std::thread;
+    use test::{black_box, Bencher};
+
+    const BENCH_ROOMS: u64 = 5000;
+    const BENCH_THREADS: usize = 8;
+    const BENCH_OPS_PER_THREAD: usize = 500;
+
+    fn add_rooms(manager: &RoomManager, count: u64) -> Vec<(RoomId, String)> {
+        (0..count)
+            .map(|i| {
+                let id = RoomManager::crypt(i);
+                let shared_data = Arc::new(SharedRoomData::new(format!("room {}", i)));
+                manager.register(id, mpsc::channel(1).0, shared_data);
+                (id, RoomManager::code_to_str(id))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rooms_move_between_states() {
+        let manager = RoomManager::new();
+        let rooms = add_rooms(&manager, 3);
+        let (id, code) = &rooms[0];
+        assert_eq!(manager.list().rooms.len(), 3);
+
+        manager.mark_as_playing(*id);
+        assert!(manager.get_tx(code).is_none());
+        assert_eq!((manager.room_count(), manager.playing_rooms()), (2, 1));
+        assert!(manager.list().rooms.iter().all(|(c, _)| c != code));
+
+        // Only the first transition counts.
+        manager.mark_as_playing(*id);
+        assert_eq!((manager.room_count(), manager.playing_rooms()), (2, 1));
+
+        manager.unmark_as_playing(*id);
+        assert!(manager.get_tx(code).is_some());
+        manager.mark_as_playing(*id);
+        block_on(manager.remove(*id));
+        assert_eq!((manager.room_count(), manager.playing_rooms()), (2, 0));
+        assert_eq!(manager.list().rooms.len(), 2);
+    }
+
+    #[test]
+    fn rooms_are_filtered_sorted_and_paged() {
+        let manager = RoomManager::new();
+        let rooms = add_rooms(&manager, 45);
+        for (i, (id, _)) in rooms.iter().enumerate() {
+            let data = &manager.rooms.get(id).unwrap().shared_data;
+            data.update_player_count(i as u16 % 5);
+            if i % 2 == 0 {
+                let mut settings = data.settings();
+                settings.balls = 3;
+                assert!(data.update_settings(settings));
+            }
+        }
+        let (full_id, full_code) = &rooms[7];
+        // Room 7 has 2 players.
+        manager
+            .rooms
+            .get(full_id)
+            .unwrap()
+            .shared_data
+            .update_player_count(MAX_PLAYERS - 2);
+        assert!(manager.get_tx(full_code).is_none());
+        let list = manager.build_list();
+
+        let (matching, page) = list.query(&RoomQuery::default());
+        assert_eq!((matching, page.len()), (45, ROOMS_PER_PAGE));
+        assert_eq!(&page[0].0, full_code);
+        assert!(page
+            .windows(2)
+            .all(|w| w[0].1.player_count() >= w[1].1.player_count()));
+        let page_of = |page| RoomQuery {
+            page,
+            ..Default::default()
+        };
+        assert_eq!(list.query(&page_of(2)).1.len(), 5);
+        assert!(list.query(&page_of(3)).1.is_empty());
+
+        let mut query = RoomQuery::default();
+        query.filter.balls = Some(3);
+        query.filter.name = "ROOM 1".to_owned();
+        query.sort = RoomSort::Name;
+        let names = |query: &RoomQuery| {
+            list.query(query)
+                .1
+                .iter()
+                .map(|(_, data)| data.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&query),
+            ["room 10", "room 12", "room 14", "room 16", "room 18"]
+        );
+        query.filter = Default::default();
+        query.filter.free_slots = true;
+        assert_eq!(list.query(&query).0, 44);
+    }
+
+    #[test]
+    fn subscriptions_get_changes_to_their_page() {
+        let manager = RoomManager::new();
+        let rooms = add_rooms(&manager, 3);
+        let query = RoomQuery {
+            sort: RoomSort::Name,
+            ..Default::default()
+        };
+        let list = manager.build_list();
+        let (matching, page) = list.query(&query);
+        let mut subscription = RoomSubscription::new(query, &page, matching, 0);
+        assert!(subscription.update(&list, 0).is_none());
+
+        let (id, code) = &rooms[1];
+        manager
+            .rooms
+            .get(id)
+            .unwrap()
+            .shared_data
+            .update_player_count(1);
+        manager.mark_as_playing(rooms[2].0);
+        let list = manager.build_list();
+        let update = subscription.update(&list, 1).unwrap();
+        assert_eq!((update.matching, update.playing), (2, 1));
+        assert_eq!(update.changed.len(), 1);
+        assert_eq!(&update.changed[0].0, code);
+        assert_eq!(update.removed, [rooms[2].1.clone()]);
+        assert!(subscription.update(&list, 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn joining_a_room_on_another_node_redirects() {
+        let directory = Arc::new(MemoryDirectory::default());
+        let a = RoomManager::with_directory(directory.clone(), "wss://a.example".to_owned());
+        let b = RoomManager::with_directory(directory, "wss://b.example".to_owned());
+        let creator = PeerAddr::Unix;
+
+        let code = match a.spawn(creator, "room".to_owned()).await {
+            Ok((code, _)) => code,
+            Err(_) => panic!("spawn failed"),
+        };
+        assert!(matches!(a.locate(&code).await, RoomLocation::Local(_)));
+        assert!(
+            matches!(b.locate(&code).await, RoomLocation::Remote(node) if node == "wss://a.example")
+        );
+        assert!(matches!(b.locate("T48W1GVJ").await, RoomLocation::Missing));
+        assert!(matches!(b.locate("../../x").await, RoomLocation::Missing));
+
+        a.remove(RoomManager::str_to_code(&code).unwrap()).await;
+        assert!(matches!(b.locate(&code).await, RoomLocation::Missing));
+    }
+
+    #[tokio::test]
+    async fn nodes_started_together_claim_different_codes() {
+        let directory = Arc::new(MemoryDirectory::default());
+        let a = RoomManager::with_directory(directory.clone(), "wss://a.example".to_owned());
+        let b = RoomManager::with_directory(directory, "wss://b.example".to_owned());
+        let start = |node: &RoomManager| node.next_room_counter.load(Ordering::Relaxed);
+        let (a_start, b_start) = (start(&a), start(&b));
+
+        // Every code is claimed on the first try, none was taken by the other node.
+        let claims = MAX_CODE_CLAIMS as u64 * 4;
+        for _ in 0..claims {
+            assert!(a.claim_code().await.is_some());
+            assert!(b.claim_code().await.is_some());
+        }
+        assert_eq!(start(&a) - a_start, claims);
+        assert_eq!(start(&b) - b_start, claims);
+    }
+
+    #[test]
+    fn quick_play_prefers_quick_play_rooms_over_other_waiting_rooms() {
+        let manager = RoomManager::new();
+        let register = |id, shared_data: SharedRoomData, players| {
+            let (tx, _) = mpsc::channel(1);
+            shared_data.update_player_count(players);
+            manager.register(id, tx.clone(), Arc::new(shared_data));
+            tx
+        };
+
+        register(0, SharedRoomData::new("empty".to_owned()), 0);
+        assert!(manager.find_quick_play_room().is_none());
+        register(1, SharedRoomData::new("full".to_owned()), MAX_PLAYERS);
+        assert!(manager.find_quick_play_room().is_none());
+
+        let normal = register(2, SharedRoomData::new("normal".to_owned()), 2);
+        let found = manager.find_quick_play_room().expect("normal room");
+        assert!(found.same_receiver(&normal));
+        manager.mark_as_playing(2);
+        assert!(manager.find_quick_play_room().is_none());
+
+        let normal = register(3, SharedRoomData::new("normal".to_owned()), 3);
+        let quick_play = register(4, SharedRoomData::new_quick_play("quick".to_owned()), 1);
+        let found = manager.find_quick_play_room().expect("quick-play room");
+        assert!(found.same_receiver(&quick_play) && !found.same_receiver(&normal));
+    }
+
+    /// The previous registry: every lobby action locks one global mutex,
+    /// and every list request copies all rooms.
+    struct GlobalLockRegistry {
+        rooms: Mutex<(HashMap<RoomId, RoomData>, HashMap<RoomId, RoomData>)>,
+    }
+
+    impl GlobalLockRegistry {
+        fn new(manager: RoomManager) -> Self {
+            let rooms = manager.rooms.into_iter().collect();
+            Self {
+                rooms: Mutex::new((rooms, HashMap::new())),
+            }
+        }
+
+        fn get_tx(&self, room_id: &str) -> Option<Sender<MessageToInbox>> {
+            let rooms = block_on(self.rooms.lock());
+            let id = RoomManager::str_to_code(room_id)?;
+            rooms.0.get(&id).map(|data| data.sender.clone())
+        }
+
+        fn list(&self) -> Vec<(String, SharedRoomData)> {
+            let rooms = block_on(self.rooms.lock());
+            rooms
+                .0
+                .iter()
+                .map(|(&id, data)| {
+                    (
+                        RoomManager::code_to_str(id),
+                        data.shared_data.deref().clone(),
+                    )
+                })
+                .collect()
+        }
+
+        fn toggle_playing(&self, id: RoomId) {
+            let mut rooms = block_on(self.rooms.lock());
+            let (waiting, playing) = &mut *rooms;
+            if let Some(data) = waiting.remove(&id) {
+                playing.insert(id, data);
+            } else if let Some(data) = playing.remove(&id) {
+                waiting.insert(id, data);
+            }
+        }
+    }
+
+    /// Lobby traffic from several threads: mostly joins by code, some listing,
+    /// and rooms starting and ending matches.
+    fn lobby_traffic(
+        rooms: &[(RoomId, String)],
+        get_tx: impl Fn(&str) + Sync,
+        list: impl Fn() + Sync,
+        toggle_playing: impl Fn(RoomId) + Sync,
+    ) {
+        thread::scope(|scope| {
+            for t in 0..BENCH_THREADS {
+                let (get_tx, list, toggle_playing) = (&get_tx, &list, &toggle_playing);
+                scope.spawn(move || {
+                    for i in 0..BENCH_OPS_PER_THREAD {
+                        let (id, code) = &rooms[(t * 7919 + i * 104_729) % rooms.len()];
+                        match i % 20 {
+                            0 => list(),
+                            1 => toggle_playing(*id),
+                            _ => get_tx(code),
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_lobby_global_lock(b: &mut Bencher) {
+        let manager = RoomManager::new();
+        let rooms = add_rooms(&manager, BENCH_ROOMS);
+        let registry = GlobalLockRegistry::new(manager);
+        b.iter(|| {
+            lobby_traffic(
+                &rooms,
+                |code| drop(black_box(registry.get_tx(code))),
+                || drop(black_box(registry.list())),
+                |id| registry.toggle_playing(id),
+            )
+        });
+    }
+
+    #[bench]
+    fn bench_lobby_sharded(b: &mut Bencher) {
+        let manager = RoomManager::new();
+        let rooms = add_rooms(&manager, BENCH_ROOMS);
+        b.iter(|| {
+            lobby_traffic(
+                &rooms,
+                |code| drop(black_box(manager.get_tx(code))),
+                || drop(black_box(manager.list())),
+                // One state change like the global lock registry, without the match log.
+                |id| {
+                    if manager
+                        .transition(id, RoomState::Waiting, RoomState::Playing)
+                        .is_none()
+                    {
+                        manager.transition(id, RoomState::Playing, RoomState::Waiting);
+                    }
+                },
+            )
+        });
+    }
+
+    #[bench]
+    fn bench_list_global_lock(b: &mut Bencher) {
+        let manager = RoomManager::new();
+        add_rooms(&manager, BENCH_ROOMS);
+        let registry = GlobalLockRegistry::new(manager);
+        b.iter(|| black_box(registry.list()));
+    }
+
+    #[bench]
+    fn bench_list_cached(b: &mut Bencher) {
+        let manager = RoomManager::new();
+        add_rooms(&manager, BENCH_ROOMS);
+        b.iter(|| black_box(manager.list()));
     }
 }
@@ -1,10 +1,16 @@
-use crate::protocol::UpdateSettings;
-use crate::room::MatchTime;
+use crate::powerup::{PowerUpFrequency, PowerUpSet};
+use crate::protocol::{serialize_if_supported, UpdateSettings};
+use crate::room::{MatchTime, TickRate, MAX_PLAYERS};
+use crate::validation::Validate;
+use crate::version::Features;
 use atomic::{Atomic, Ordering};
 use serde::{Serialize, Serializer};
 const_assert!(Atomic::<MatchTime>::is_lock_free());
+const_assert!(Atomic::<TickRate>::is_lock_free());
 const_assert!(Atomic::<u8>::is_lock_free());
 const_assert!(Atomic::<u16>::is_lock_free());
+const_assert!(Atomic::<PowerUpSet>::is_lock_free());
+const_assert!(Atomic::<PowerUpFrequency>::is_lock_free());
 
 pub struct AtomicRelaxed<T: Copy>(Atomic<T>);
 
@@ -16,8 +22,17 @@ pub struct SharedRoomData {
     nr_balls: AtomicRelaxed<u8>,
     spin_towards_center: AtomicRelaxed<bool>,
     power_ups: AtomicRelaxed<bool>,
+    #[serde(serialize_with = "serialize_power_up_setting")]
+    enabled_power_ups: AtomicRelaxed<PowerUpSet>,
+    #[serde(serialize_with = "serialize_power_up_setting")]
+    power_up_frequency: AtomicRelaxed<PowerUpFrequency>,
     match_time: AtomicRelaxed<MatchTime>,
+    #[serde(serialize_with = "serialize_tick_rate")]
+    tick_rate: AtomicRelaxed<TickRate>,
     player_count: AtomicRelaxed<u16>,
+    /// Made by the matchmaker, starts its matches by itself.
+    #[serde(skip)]
+    quick_play: bool,
 }
 
 impl<T: Copy> AtomicRelaxed<T> {
@@ -39,6 +54,22 @@ impl<T: Copy + Serialize> Serialize for AtomicRelaxed<T> {
     }
 }
 
+/// Serializes a power-up setting, for clients that know about them.
+fn serialize_power_up_setting<T: Copy + Serialize, S: Serializer>(
+    setting: &AtomicRelaxed<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(setting, Features::POWER_UP_SETTINGS, serializer)
+}
+
+/// Serializes the tick rate, for clients that know about it.
+fn serialize_tick_rate<S: Serializer>(
+    tick_rate: &AtomicRelaxed<TickRate>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serialize_if_supported(tick_rate, Features::TICK_RATE, serializer)
+}
+
 impl Clone for SharedRoomData {
     fn clone(&self) -> Self {
         Self {
@@ -47,14 +78,19 @@ impl Clone for SharedRoomData {
             nr_balls: AtomicRelaxed(Atomic::new(self.nr_balls.load())),
             spin_towards_center: AtomicRelaxed(Atomic::new(self.spin_towards_center.load())),
             power_ups: AtomicRelaxed(Atomic::new(self.power_ups.load())),
+            enabled_power_ups: AtomicRelaxed(Atomic::new(self.enabled_power_ups.load())),
+            power_up_frequency: AtomicRelaxed(Atomic::new(self.power_up_frequency.load())),
             match_time: AtomicRelaxed(Atomic::new(self.match_time.load())),
+            tick_rate: AtomicRelaxed(Atomic::new(self.tick_rate.load())),
             player_count: AtomicRelaxed(Atomic::new(self.player_count.load())),
+            quick_play: self.quick_play,
         }
     }
 }
 
 impl SharedRoomData {
-    // Relaxed ordering is fine now as there are no multiple threads atm.
+    // Relaxed ordering is fine: only the room writes, and the fields are independent values
+    // that other threads only read to list the room.
 
     /// Creates new shared room data.
     pub fn new(name: String) -> Self {
@@ -64,11 +100,55 @@ impl SharedRoomData {
             nr_balls: AtomicRelaxed(Atomic::new(2)),
             spin_towards_center: AtomicRelaxed(Atomic::new(false)),
             power_ups: AtomicRelaxed(Atomic::new(true)),
+            enabled_power_ups: AtomicRelaxed(Atomic::new(PowerUpSet::all())),
+            power_up_frequency: AtomicRelaxed(Atomic::new(PowerUpFrequency::Normal)),
             match_time: AtomicRelaxed(Atomic::new(MatchTime::Short)),
+            tick_rate: AtomicRelaxed(Atomic::new(TickRate::default())),
             player_count: AtomicRelaxed(Atomic::new(0)),
+            quick_play: false,
+        }
+    }
+
+    /// Creates shared room data for a quick-play room, with the default settings.
+    pub fn new_quick_play(name: String) -> Self {
+        Self {
+            quick_play: true,
+            ..Self::new(name)
         }
     }
 
+    /// Is the room made by the matchmaker?
+    #[inline]
+    pub fn is_quick_play(&self) -> bool {
+        self.quick_play
+    }
+
+    /// Gets the room name.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks if another player fits in the room.
+    #[inline]
+    pub fn has_free_slots(&self) -> bool {
+        self.player_count() < MAX_PLAYERS
+    }
+
+    /// Compares what is shown in the room list.
+    pub fn listing_eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.nr_teams() == other.nr_teams()
+            && self.nr_balls() == other.nr_balls()
+            && self.spin_towards_center() == other.spin_towards_center()
+            && self.power_ups() == other.power_ups()
+            && self.enabled_power_ups() == other.enabled_power_ups()
+            && self.power_up_frequency() == other.power_up_frequency()
+            && self.match_time() == other.match_time()
+            && self.tick_rate() == other.tick_rate()
+            && self.player_count() == other.player_count()
+    }
+
     /// Update the player count.
     #[inline]
     pub fn update_player_count(&self, delta: u16) {
@@ -110,6 +190,18 @@ impl SharedRoomData {
         self.power_ups.load()
     }
 
+    /// Power-up types that may spawn.
+    #[inline]
+    pub fn enabled_power_ups(&self) -> PowerUpSet {
+        self.enabled_power_ups.load()
+    }
+
+    /// How often power-ups spawn.
+    #[inline]
+    pub fn power_up_frequency(&self) -> PowerUpFrequency {
+        self.power_up_frequency.load()
+    }
+
     /// Spin towards center?
     #[inline]
     pub fn spin_towards_center(&self) -> bool {
@@ -148,10 +240,21 @@ impl SharedRoomData {
         self.match_time.load()
     }
 
+    /// Server tick rate.
+    #[inline]
+    pub fn tick_rate(&self) -> TickRate {
+        self.tick_rate.load()
+    }
+
+    /// Falls back to the default tick rate, for clients that don't support others.
+    pub fn reset_tick_rate(&self) {
+        self.tick_rate.store(TickRate::default());
+    }
+
     /// Update settings.
     pub fn update_settings(&self, update: UpdateSettings) -> bool {
         // First, verify.
-        if update.balls < 1 || update.balls > 8 {
+        if update.validate().is_err() {
             return false;
         }
 
@@ -160,6 +263,9 @@ impl SharedRoomData {
         self.power_ups.store(update.power_ups);
         self.match_time.store(update.match_time);
         self.spin_towards_center.store(update.spin_towards_center);
+        self.enabled_power_ups.store(update.enabled_power_ups);
+        self.power_up_frequency.store(update.power_up_frequency);
+        self.tick_rate.store(update.tick_rate);
 
         debug!("Updated settings: {:?}", self.settings());
 
@@ -173,6 +279,9 @@ impl SharedRoomData {
             power_ups: self.power_ups(),
             match_time: self.match_time(),
             spin_towards_center: self.spin_towards_center(),
+            enabled_power_ups: self.enabled_power_ups(),
+            power_up_frequency: self.power_up_frequency(),
+            tick_rate: self.tick_rate(),
         }
     }
 }
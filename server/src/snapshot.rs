@@ -0,0 +1,328 @@
+use crate::ball::{Ball, HitPair};
+use crate::player::{ClientId, SeqNr};
+use crate::player_container::PlayerContainer;
+use crate::protocol::{serialize_frame_time, PowerUpEvent, SyncMessage};
+use crate::quantize::{quantize_angle, CompactBallData};
+use crate::room::FrameTime;
+use fnv::FnvHashMap;
+use serde::Serialize;
+use smallvec::SmallVec;
+use std::collections::VecDeque;
+
+/// Number of snapshots to keep, clients that acknowledged an older frame get a keyframe.
+pub const SYNC_HISTORY_LEN: usize = 32;
+
+/// Quantized state of a ball.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct BallState {
+    data: CompactBallData,
+    rally: u8,
+    hit_pair: HitPair,
+}
+
+impl BallState {
+    fn new(ball: &Ball) -> Self {
+        Self {
+            data: CompactBallData::from(&ball.characteristics()),
+            rally: ball.last_rally(),
+            hit_pair: ball.last_hit_pair(),
+        }
+    }
+}
+
+/// Quantized state of a player.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+struct PlayerState {
+    pos: u16,
+    seq_nr: SeqNr,
+}
+
+/// Quantized state of a room at a frame, the base for deltas.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    frame: SeqNr,
+    balls: SmallVec<[BallState; 3]>,
+    /// Sorted by client id.
+    players: Vec<(ClientId, PlayerState)>,
+}
+
+impl Snapshot {
+    /// Captures the current state.
+    pub fn capture(frame: SeqNr, balls: &[Ball], players: &PlayerContainer) -> Self {
+        let mut players = players
+            .iter()
+            .map(|(&id, player)| {
+                let player = player.borrow();
+                (
+                    id,
+                    PlayerState {
+                        pos: quantize_angle(player.current_pos()),
+                        seq_nr: player.move_seq_nr(),
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        players.sort_unstable_by_key(|&(id, _)| id);
+
+        Self {
+            frame,
+            balls: balls.iter().map(BallState::new).collect(),
+            players,
+        }
+    }
+
+    /// Gets the frame of the snapshot.
+    #[inline]
+    pub fn frame(&self) -> SeqNr {
+        self.frame
+    }
+
+    /// Gets the state of a player, players that didn't exist start at zero.
+    fn player(&self, id: ClientId) -> PlayerState {
+        self.players
+            .binary_search_by_key(&id, |&(id, _)| id)
+            .map(|index| self.players[index].1)
+            .unwrap_or_default()
+    }
+}
+
+/// Ball changes since the base, numbers are wrapping differences of the quantized values.
+#[derive(Debug, Serialize)]
+pub struct BallDelta {
+    pub index_rally_packed: u8,
+    pub hit_pair: HitPair,
+    pub flags: u8,
+    pub pos: [i16; 2],
+    pub angle: i16,
+    pub speed: i16,
+    pub spin: i16,
+}
+
+/// Player changes since the base, numbers are wrapping differences of the quantized values.
+#[derive(Debug, Serialize)]
+pub struct PlayerDelta {
+    pub client_id: ClientId,
+    pub pos: i16,
+    pub seq_nr: i32,
+}
+
+/// Sync relative to a frame the client acknowledged.
+/// The client keeps its reconstructed snapshots of the last `SYNC_HISTORY_LEN` frames,
+/// missing balls and players in the base start at zero.
+#[derive(Debug, Serialize)]
+pub struct DeltaSyncMessage<'a> {
+    #[serde(serialize_with = "serialize_frame_time")]
+    pub frame_time: FrameTime,
+    pub frame: SeqNr,
+    /// The frame the deltas are relative to, None for a keyframe.
+    pub base: Option<SeqNr>,
+    pub ball_count: u8,
+    pub balls: SmallVec<[BallDelta; 3]>,
+    pub players: SmallVec<[PlayerDelta; 4]>,
+    pub power_ups: &'a [PowerUpEvent],
+}
+
+impl<'a> DeltaSyncMessage<'a> {
+    /// Creates the delta from `base` to `current`, a keyframe if there's no base.
+    /// Balls are sent when they changed or had an event in `sync`.
+    pub fn new(sync: &'a SyncMessage, base: Option<&Snapshot>, current: &Snapshot) -> Self {
+        let empty = Snapshot::default();
+        let base_snapshot = base.unwrap_or(&empty);
+
+        let balls = current
+            .balls
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ball)| {
+                let old = base_snapshot.balls.get(i).copied().unwrap_or_default();
+                let flags = sync
+                    .ball_syncs
+                    .iter()
+                    .filter(|s| (s.index_rally_packed >> 4) as usize == i)
+                    .fold(0, |flags, s| flags | s.flags);
+                if base.is_some() && flags == 0 && *ball == old {
+                    return None;
+                }
+                Some(BallDelta {
+                    index_rally_packed: ((i as u8) << 4) | ball.rally,
+                    hit_pair: ball.hit_pair,
+                    flags,
+                    pos: [
+                        ball.data.pos[0].wrapping_sub(old.data.pos[0]),
+                        ball.data.pos[1].wrapping_sub(old.data.pos[1]),
+                    ],
+                    angle: ball.data.angle.wrapping_sub(old.data.angle) as i16,
+                    speed: ball.data.speed.wrapping_sub(old.data.speed) as i16,
+                    spin: ball.data.spin.wrapping_sub(old.data.spin),
+                })
+            })
+            .collect();
+
+        let players = current
+            .players
+            .iter()
+            .filter_map(|&(client_id, player)| {
+                let old = base_snapshot.player(client_id);
+                if base.is_some() && player == old {
+                    return None;
+                }
+                Some(PlayerDelta {
+                    client_id,
+                    pos: player.pos.wrapping_sub(old.pos) as i16,
+                    seq_nr: player.seq_nr.wrapping_sub(old.seq_nr) as i32,
+                })
+            })
+            .collect();
+
+        Self {
+            frame_time: sync.frame_time,
+            frame: current.frame,
+            base: base.map(Snapshot::frame),
+            ball_count: current.balls.len() as u8,
+            balls,
+            players,
+            power_ups: &sync.power_ups,
+        }
+    }
+}
+
+/// Recent snapshots and the last frame each client acknowledged.
+#[derive(Default)]
+pub struct SyncHistory {
+    snapshots: VecDeque<Snapshot>,
+    acks: FnvHashMap<ClientId, SeqNr>,
+}
+
+impl SyncHistory {
+    /// Adds the snapshot of a new frame.
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() == SYNC_HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Gets the newest snapshot.
+    pub fn latest(&self) -> Option<&Snapshot> {
+        self.snapshots.back()
+    }
+
+    /// Records that a client received a frame. Frames we never sent are ignored.
+    pub fn ack(&mut self, id: ClientId, frame: SeqNr) {
+        if self.snapshots.iter().any(|s| s.frame == frame) {
+            let acked = self.acks.entry(id).or_insert(frame);
+            *acked = (*acked).max(frame);
+        }
+    }
+
+    /// Gets the snapshot the next delta for a client is relative to.
+    /// None if the client needs a keyframe.
+    pub fn base_for(&self, id: ClientId) -> Option<&Snapshot> {
+        let frame = *self.acks.get(&id)?;
+        self.snapshots.iter().find(|s| s.frame == frame)
+    }
+
+    /// Forgets a client.
+    pub fn remove(&mut self, id: ClientId) {
+        self.acks.remove(&id);
+    }
+
+    /// Forgets everything, the next syncs are keyframes.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.acks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Applies a delta the way a client does.
+    fn apply(base: &Snapshot, delta: &DeltaSyncMessage) -> Snapshot {
+        let mut next = base.clone();
+        next.frame = delta.frame;
+        next.balls
+            .resize(delta.ball_count as usize, BallState::default());
+        for d in &delta.balls {
+            let ball = &mut next.balls[(d.index_rally_packed >> 4) as usize];
+            let data = &mut ball.data;
+            data.pos[0] = data.pos[0].wrapping_add(d.pos[0]);
+            data.pos[1] = data.pos[1].wrapping_add(d.pos[1]);
+            data.angle = data.angle.wrapping_add(d.angle as u16);
+            data.speed = data.speed.wrapping_add(d.speed as u16);
+            data.spin = data.spin.wrapping_add(d.spin);
+            ball.rally = d.index_rally_packed & 15;
+            ball.hit_pair = d.hit_pair;
+        }
+        for d in &delta.players {
+            let old = next.player(d.client_id);
+            let new = PlayerState {
+                pos: old.pos.wrapping_add(d.pos as u16),
+                seq_nr: old.seq_nr.wrapping_add(d.seq_nr as u32),
+            };
+            match next
+                .players
+                .binary_search_by_key(&d.client_id, |&(id, _)| id)
+            {
+                Ok(index) => next.players[index].1 = new,
+                Err(index) => next.players.insert(index, (d.client_id, new)),
+            }
+        }
+        next
+    }
+
+    fn snapshot(frame: SeqNr, x: i16, angle: u16, players: &[(ClientId, u16)]) -> Snapshot {
+        let ball = BallState {
+            data: CompactBallData {
+                pos: [x, 300],
+                angle,
+                speed: 1024,
+                spin: -20,
+            },
+            rally: 1,
+            hit_pair: HitPair::none(),
+        };
+        Snapshot {
+            frame,
+            balls: SmallVec::from_slice(&[ball, ball]),
+            players: players
+                .iter()
+                .map(|&(id, pos)| (id, PlayerState { pos, seq_nr: frame }))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn deltas_reconstruct_the_snapshot() {
+        let sync = SyncMessage {
+            frame_time: 0,
+            client_syncs: Vec::new(),
+            ball_syncs: SmallVec::new(),
+            power_ups: SmallVec::new(),
+        };
+        let first = snapshot(3, -100, 65530, &[(0, 10), (1, 20)]);
+        let second = snapshot(6, 32000, 4, &[(1, 20), (2, 65535)]);
+
+        let keyframe = DeltaSyncMessage::new(&sync, None, &first);
+        assert_eq!(keyframe.base, None);
+        let client = apply(&Snapshot::default(), &keyframe);
+        assert_eq!(client.balls, first.balls);
+        assert_eq!(client.players, first.players);
+
+        let delta = DeltaSyncMessage::new(&sync, Some(&first), &second);
+        assert_eq!(delta.base, Some(3));
+        // Player 1 didn't move, only the seq nr changed.
+        assert_eq!(delta.players.len(), 2);
+        let client = apply(&client, &delta);
+        assert_eq!(client.balls, second.balls);
+        assert!(second
+            .players
+            .iter()
+            .all(|&(id, state)| client.player(id) == state));
+
+        // Nothing changed, nothing to send.
+        let delta = DeltaSyncMessage::new(&sync, Some(&second), &second);
+        assert!(delta.balls.is_empty() && delta.players.is_empty());
+    }
+}
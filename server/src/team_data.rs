@@ -1,12 +1,36 @@
+use smallvec::SmallVec;
+
 // Delta verification, same as in the client.
 // Have to get the exact same value as JS.
 pub const MAX_MOVE: f32 = 0.157_079_64 * 2.0;
 
 pub const SLOWDOWN_FACTOR: f32 = 1.0 / 8.0;
 
+/// Bitmask of teams.
+pub type TeamMask = u8;
+
 #[derive(Debug, Copy, Clone)]
+pub enum TeamModifierKind {
+    /// Multiplies the maximum move speed.
+    Speed(f32),
+    /// Multiplies the player width.
+    Width(f32),
+    /// Inverts the controls, two inversions cancel each other out.
+    InvertedControls,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct TeamModifier {
+    kind: TeamModifierKind,
+    time_left: f32,
+}
+
+#[derive(Debug, Clone)]
 pub struct TeamData {
+    modifiers: SmallVec<[TeamModifier; 4]>,
     max_move_factor: f32,
+    width_factor: f32,
+    inverted_controls: bool,
 }
 
 impl TeamData {
@@ -16,15 +40,96 @@ impl TeamData {
     }
 
     #[inline]
-    pub fn set_speed(&mut self, factor: f32) {
-        self.max_move_factor = MAX_MOVE * factor;
+    pub fn width_factor(&self) -> f32 {
+        self.width_factor
+    }
+
+    #[inline]
+    pub fn inverted_controls(&self) -> bool {
+        self.inverted_controls
+    }
+
+    /// Adds a modifier that expires after the duration (in seconds).
+    pub fn add_modifier(&mut self, kind: TeamModifierKind, duration: f32) {
+        self.modifiers.push(TeamModifier {
+            kind,
+            time_left: duration,
+        });
+        self.recalculate();
+    }
+
+    /// Ticks the modifiers and removes the expired ones.
+    pub fn tick(&mut self, delta: f32) {
+        let count = self.modifiers.len();
+        self.modifiers.retain(|m| {
+            m.time_left -= delta;
+            m.time_left > 0.0
+        });
+        if self.modifiers.len() != count {
+            self.recalculate();
+        }
+    }
+
+    /// Recalculates the combined effect of the modifiers.
+    fn recalculate(&mut self) {
+        let mut speed = 1.0;
+        let mut width = 1.0;
+        let mut inverted = false;
+        for m in self.modifiers.iter() {
+            match m.kind {
+                TeamModifierKind::Speed(factor) => speed *= factor,
+                TeamModifierKind::Width(factor) => width *= factor,
+                TeamModifierKind::InvertedControls => inverted = !inverted,
+            }
+        }
+        self.max_move_factor = MAX_MOVE * speed;
+        self.width_factor = width;
+        self.inverted_controls = inverted;
     }
 }
 
 impl Default for TeamData {
     fn default() -> Self {
         Self {
+            modifiers: SmallVec::new(),
             max_move_factor: MAX_MOVE,
+            width_factor: 1.0,
+            inverted_controls: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifiers_stack() {
+        let mut td = TeamData::default();
+        td.add_modifier(TeamModifierKind::Speed(0.5), 1.0);
+        td.add_modifier(TeamModifierKind::Speed(0.5), 2.0);
+        td.add_modifier(TeamModifierKind::Width(2.0), 1.0);
+        assert_eq!(td.max_move_factor(), MAX_MOVE * 0.25);
+        assert_eq!(td.width_factor(), 2.0);
+
+        td.add_modifier(TeamModifierKind::InvertedControls, 1.0);
+        assert!(td.inverted_controls());
+        td.add_modifier(TeamModifierKind::InvertedControls, 2.0);
+        assert!(!td.inverted_controls());
+    }
+
+    #[test]
+    fn modifiers_expire_on_their_own() {
+        let mut td = TeamData::default();
+        td.add_modifier(TeamModifierKind::Speed(0.5), 1.0);
+        td.add_modifier(TeamModifierKind::Width(2.0), 2.0);
+
+        td.tick(0.5);
+        assert_eq!(td.max_move_factor(), MAX_MOVE * 0.5);
+        td.tick(0.5);
+        assert_eq!(td.max_move_factor(), MAX_MOVE);
+        assert_eq!(td.width_factor(), 2.0);
+        td.tick(1.0);
+        assert_eq!(td.width_factor(), 1.0);
+    }
+}
@@ -0,0 +1,99 @@
+use crate::config::env_or;
+use chrono::Local;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+
+/// Settings for the optional TLS listener.
+pub struct TlsConfig {
+    /// Address of the TLS listener.
+    pub listen: String,
+    /// PEM certificate chain, TLS is disabled if empty.
+    pub cert: String,
+    /// PEM private key (PKCS#8 or RSA).
+    pub key: String,
+}
+
+impl TlsConfig {
+    /// Reads the config from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            listen: env_or("PINGBONG_TLS_LISTEN", "127.0.0.1:4243".to_string()),
+            cert: env_or("PINGBONG_TLS_CERT", String::new()),
+            key: env_or("PINGBONG_TLS_KEY", String::new()),
+        }
+    }
+
+    /// Checks if the TLS listener is enabled.
+    pub fn is_enabled(&self) -> bool {
+        !self.cert.is_empty()
+    }
+
+    /// Loads the certificate and key from disk.
+    fn load(&self) -> io::Result<TlsAcceptor> {
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+
+        let cert_chain = certs(&mut BufReader::new(File::open(&self.cert)?))
+            .map_err(|_| invalid("invalid certificate"))?;
+        let key = {
+            let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(&self.key)?))
+                .map_err(|_| invalid("invalid key"))?;
+            if keys.is_empty() {
+                keys = rsa_private_keys(&mut BufReader::new(File::open(&self.key)?))
+                    .map_err(|_| invalid("invalid key"))?;
+            }
+            keys.pop().ok_or_else(|| invalid("no key found"))?
+        };
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(cert_chain, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+}
+
+/// TLS acceptor that can swap in a new certificate while running.
+pub struct Tls {
+    config: &'static TlsConfig,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl Tls {
+    /// Loads the initial certificate.
+    pub fn new(config: &'static TlsConfig) -> io::Result<Self> {
+        Ok(Self {
+            config,
+            acceptor: RwLock::new(config.load()?),
+        })
+    }
+
+    /// Gets the current acceptor.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().expect("tls acceptor").clone()
+    }
+
+    /// Reloads the certificate and key, keeps the old ones on failure.
+    fn reload(&self) {
+        let now = Local::now().format("%d-%m %H:%M");
+        match self.config.load() {
+            Ok(acceptor) => {
+                *self.acceptor.write().expect("tls acceptor") = acceptor;
+                println!("[{}] reloaded TLS certificate", now);
+            }
+            Err(e) => println!("[{}] failed to reload TLS certificate: {}", now, e),
+        }
+    }
+}
+
+/// Reloads the certificate whenever the process receives SIGHUP.
+pub async fn reload_on_sighup(tls: Arc<Tls>) {
+    let mut hangups = signal(SignalKind::hangup()).expect("SIGHUP handler");
+    while hangups.recv().await.is_some() {
+        tls.reload();
+    }
+}
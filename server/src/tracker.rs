@@ -1,4 +1,4 @@
-use crate::powerup::PowerUpEffectType;
+use crate::powerup::{PowerUpEffectType, PowerUpSet, POWER_UP_DEFINITIONS};
 use crate::room::{CIRCLE_RADIUS, FIELD_HEIGHT, FIELD_WIDTH};
 use crate::vector::Vector;
 use rand::rngs::SmallRng;
@@ -38,17 +38,49 @@ impl Tracker {
         )
     }
 
-    /// Returns the next powerup type.
-    pub fn next_powerup_type(&mut self) -> PowerUpEffectType {
-        match self.rng.gen_range(0..=4) {
-            0 => PowerUpEffectType::GrowOwnTeam,
-            1 => PowerUpEffectType::BonusPoints,
-            2 => PowerUpEffectType::SplitRGB,
-            3 => PowerUpEffectType::RotateField,
-            4 => PowerUpEffectType::SlowDown,
-            // Should never happen, but I don't want to pollute the machine code with error handling that will never get executed.
-            _ => PowerUpEffectType::GrowOwnTeam,
+    /// Returns the next powerup type, picked by weight from the eligible definitions.
+    /// Returns None if no power-up is eligible.
+    pub fn next_powerup_type(
+        &mut self,
+        enabled: PowerUpSet,
+        nr_teams: u8,
+        nr_balls: usize,
+    ) -> Option<PowerUpEffectType> {
+        let eligible = || {
+            POWER_UP_DEFINITIONS.iter().filter(move |d| {
+                enabled.contains(d.effect_type)
+                    && nr_teams >= d.min_teams
+                    && nr_balls <= d.max_balls as usize
+            })
+        };
+
+        let total_weight: u32 = eligible().map(|d| d.weight).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut pick = self.rng.gen_range(0..total_weight);
+        for definition in eligible() {
+            if pick < definition.weight {
+                return Some(definition.effect_type);
+            }
+            pick -= definition.weight;
+        }
+
+        // Should never happen, but I don't want to pollute the machine code with error handling that will never get executed.
+        None
+    }
+
+    /// Returns a random team, except the given team.
+    /// Returns None if there is no other team.
+    pub fn random_other_team(&mut self, nr_teams: u8, except: u8) -> Option<u8> {
+        if nr_teams < 2 {
+            return None;
         }
+
+        // Pick from the other teams by skipping over the excluded one.
+        let team = self.rng.gen_range(0..nr_teams - 1);
+        Some(if team >= except { team + 1 } else { team })
     }
 
     /// Returns the next ball characteristics.
@@ -0,0 +1,203 @@
+use crate::ball::NO_TEAM;
+use crate::player::SeqNr;
+use crate::protocol::{ClientMoveUpdate, RoomMessageFromClient, UpdateSettings};
+use crate::room::MAX_BALLS;
+
+/// Reason for rejecting a client message.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InvalidMessage {
+    /// The move delta is NaN or infinite.
+    NonFiniteDelta,
+    /// The spin is NaN or infinite.
+    NonFiniteSpin,
+    /// The hit ball can never exist.
+    BallOutOfRange(u8),
+    /// The sequence number did not increase.
+    SeqNrNotIncreasing { seq_nr: SeqNr, last: SeqNr },
+    /// The ball count is outside of the allowed range.
+    BallCount(u8),
+    /// Unknown power-ups are enabled, or power-ups are on without allowing any.
+    PowerUpSet,
+}
+
+/// Validates the contents of an untrusted client message.
+/// Only checks what can be checked without knowing the room state.
+pub trait Validate {
+    fn validate(&self) -> Result<(), InvalidMessage>;
+}
+
+impl Validate for ClientMoveUpdate {
+    fn validate(&self) -> Result<(), InvalidMessage> {
+        if !self.delta.is_finite() {
+            return Err(InvalidMessage::NonFiniteDelta);
+        }
+
+        if !self.spin.is_finite() {
+            return Err(InvalidMessage::NonFiniteSpin);
+        }
+
+        if self.ball_hit != NO_TEAM && self.ball_hit >= MAX_BALLS {
+            return Err(InvalidMessage::BallOutOfRange(self.ball_hit));
+        }
+
+        Ok(())
+    }
+}
+
+impl Validate for UpdateSettings {
+    fn validate(&self) -> Result<(), InvalidMessage> {
+        if self.balls < 1 || self.balls > MAX_BALLS {
+            return Err(InvalidMessage::BallCount(self.balls));
+        }
+
+        if !self.enabled_power_ups.is_valid()
+            || (self.power_ups && self.enabled_power_ups.is_empty())
+        {
+            return Err(InvalidMessage::PowerUpSet);
+        }
+
+        Ok(())
+    }
+}
+
+impl Validate for RoomMessageFromClient {
+    fn validate(&self) -> Result<(), InvalidMessage> {
+        match self {
+            RoomMessageFromClient::Move(update) => update.validate(),
+            RoomMessageFromClient::UpdateSettings(settings) => settings.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Validates that a sequence number comes after the last accepted one.
+pub fn validate_seq_nr(seq_nr: SeqNr, last: SeqNr) -> Result<(), InvalidMessage> {
+    if seq_nr > last {
+        Ok(())
+    } else {
+        Err(InvalidMessage::SeqNrNotIncreasing { seq_nr, last })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ball::MAX_CLIENT_SPIN;
+    use crate::player::Player;
+    use crate::shared_room_data::SharedRoomData;
+    use crate::team_data::{TeamData, TeamModifierKind, SLOWDOWN_FACTOR};
+    use crate::version::{with_version, ProtocolVersion};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    fn move_update(delta: f32, ball_hit: u8, spin: f32) -> ClientMoveUpdate {
+        ClientMoveUpdate {
+            delta,
+            seq_nr: 1,
+            ball_hit,
+            spin,
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_moves() {
+        assert!(move_update(1.0, NO_TEAM, 0.0).validate().is_ok());
+        assert!(move_update(1.0, MAX_BALLS - 1, 0.0).validate().is_ok());
+        assert_eq!(
+            move_update(f32::NAN, NO_TEAM, 0.0).validate(),
+            Err(InvalidMessage::NonFiniteDelta)
+        );
+        assert_eq!(
+            move_update(0.0, NO_TEAM, f32::NEG_INFINITY).validate(),
+            Err(InvalidMessage::NonFiniteSpin)
+        );
+        assert_eq!(
+            move_update(0.0, MAX_BALLS, 0.0).validate(),
+            Err(InvalidMessage::BallOutOfRange(MAX_BALLS))
+        );
+        assert!(validate_seq_nr(2, 1).is_ok());
+        assert!(validate_seq_nr(1, 1).is_err());
+    }
+
+    /// Picks a float, often one of the edge cases a client could send.
+    fn random_f32(rng: &mut SmallRng) -> f32 {
+        match rng.gen_range(0..8) {
+            0 => f32::NAN,
+            1 => f32::INFINITY,
+            2 => f32::NEG_INFINITY,
+            3 => f32::MAX,
+            4 => -f32::MAX,
+            _ => rng.gen_range(-1.0..1.0),
+        }
+    }
+
+    /// Feeds random messages that pass validation to the handlers and checks they keep the
+    /// player and the room settings in range.
+    #[test]
+    fn fuzz_decoded_messages() {
+        let mut rng = SmallRng::seed_from_u64(0x5eed);
+        let shared_data = SharedRoomData::new("fuzz".to_owned());
+        let mut player = Player::new("fuzz".to_owned(), 0);
+        player.setup(std::f32::consts::PI, 2, 1, 0);
+        let mut modified = TeamData::default();
+        modified.add_modifier(TeamModifierKind::Speed(SLOWDOWN_FACTOR), 10.0);
+        modified.add_modifier(TeamModifierKind::InvertedControls, 10.0);
+        let team_data = [TeamData::default(), modified];
+        let mut seq_nr = 0u32;
+        let (mut moves, mut settings) = (0, 0);
+
+        for _ in 0..100_000 {
+            // Encode messages field by field, random bytes would hardly ever decode.
+            let mut buf = if rng.gen_bool(0.5) {
+                seq_nr = seq_nr.wrapping_add(rng.gen_range(0..3));
+                let ball_hit = match rng.gen_bool(0.5) {
+                    true => NO_TEAM,
+                    false => rng.gen_range(0..MAX_BALLS + 2),
+                };
+                let update = (random_f32(&mut rng), seq_nr, ball_hit, random_f32(&mut rng));
+                crate::bincode::serialize(&(0u32, update)).unwrap()
+            } else {
+                let update = (
+                    rng.gen_range(0..MAX_BALLS + 2),
+                    rng.gen::<bool>(),
+                    rng.gen_range(0..3u32),
+                    rng.gen::<bool>(),
+                    rng.gen_range(0..1u16 << 12),
+                    rng.gen_range(0..4u32),
+                    rng.gen_range(0..4u32),
+                );
+                crate::bincode::serialize(&(3u32, update)).unwrap()
+            };
+            if rng.gen_bool(0.1) {
+                let index = rng.gen_range(0..buf.len());
+                buf[index] = rng.gen();
+            }
+
+            let msg = match with_version(ProtocolVersion::CURRENT, || {
+                crate::bincode::deserialize::<RoomMessageFromClient>(&buf)
+            }) {
+                Ok(msg) if msg.validate().is_ok() => msg,
+                _ => continue,
+            };
+            match msg {
+                RoomMessageFromClient::Move(update) => {
+                    moves += 1;
+                    player.queue_move(update, &team_data[rng.gen_range(0..2)]);
+                    let pos = player.current_pos();
+                    assert!(pos >= player.min_pos() && pos <= player.max_pos());
+                    assert!(player.spin().abs() <= MAX_CLIENT_SPIN);
+                    assert!(player.ball_hit().is_none_or(|hit| hit.id() < MAX_BALLS));
+                    player.tick(0);
+                }
+                RoomMessageFromClient::UpdateSettings(update) => {
+                    settings += 1;
+                    assert!(shared_data.update_settings(update));
+                    assert!((1..=MAX_BALLS).contains(&shared_data.nr_balls()));
+                    assert!(shared_data.enabled_power_ups().is_valid());
+                }
+                _ => {}
+            }
+        }
+        assert!(moves > 1000 && settings > 1000);
+    }
+}
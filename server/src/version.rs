@@ -0,0 +1,336 @@
+use crate::protocol::{MessageToClient, OutdatedReason};
+use bytes::Bytes;
+use serde::Serialize;
+use std::cell::Cell;
+
+/// Newest protocol version.
+pub const PROTOCOL_VERSION: u32 = 22;
+
+/// Oldest protocol version that is still accepted, the version of the shipped client.
+/// Older versions get the message formats they know, see `Features`.
+pub const MIN_PROTOCOL_VERSION: u32 = 9;
+
+/// Optional protocol features, reported to the client after negotiation.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[repr(transparent)]
+pub struct Features(u32);
+
+impl Features {
+    /// Clients are sent back to the lobby with `RoomClosed` when a room crashes.
+    pub const ROOM_CLOSED: Self = Self(1 << 0);
+    /// The server reports the negotiated version and features.
+    pub const NEGOTIATION: Self = Self(1 << 1);
+    /// Syncs are sent as deltas to the last acknowledged frame.
+    pub const DELTA_SYNC: Self = Self(1 << 2);
+    /// Ball data and client syncs use the compact fixed-point format.
+    pub const COMPACT_STATE: Self = Self(1 << 3);
+    /// Rooms can run at a higher tick rate, part of the room settings.
+    pub const TICK_RATE: Self = Self(1 << 4);
+    /// Clients answer application-level pings and get the round trip times of all players.
+    pub const LATENCY: Self = Self(1 << 5);
+    /// Match times are sent as integers and clients sync their clock with `ClockSync`.
+    pub const CLOCK_SYNC: Self = Self(1 << 6);
+    /// Joining a room on another node redirects the client to that node.
+    pub const REDIRECT: Self = Self(1 << 7);
+    /// Room lists are paged, filtered and sorted, and can be subscribed to.
+    pub const ROOM_QUERY: Self = Self(1 << 8);
+    /// Players can queue for quick-play matches.
+    pub const QUICK_PLAY: Self = Self(1 << 9);
+    /// Matches can be paused and resumed.
+    pub const PAUSE: Self = Self(1 << 10);
+    /// The power-up effects after slow down can spawn.
+    pub const POWER_UP_EFFECTS: Self = Self(1 << 11);
+    /// Several power-ups can be on the field or active at once, and are told apart by id.
+    pub const MULTIPLE_POWER_UPS: Self = Self(1 << 12);
+    /// The room settings include the enabled power-ups and their frequency.
+    pub const POWER_UP_SETTINGS: Self = Self(1 << 13);
+    /// Power-up packets target teams with a `TeamMask` instead of the activating team.
+    pub const TEAM_MASKS: Self = Self(1 << 14);
+
+    /// Checks if all features of `other` are included.
+    #[inline]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Features {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// Protocol version that was agreed on with a client.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[repr(transparent)]
+pub struct ProtocolVersion(u32);
+
+impl ProtocolVersion {
+    /// The newest version.
+    pub const CURRENT: Self = Self(PROTOCOL_VERSION);
+
+    /// Picks the version to talk to a client that supports up to `requested`.
+    /// Newer clients are expected to fall back to our version.
+    pub fn negotiate(requested: u32) -> Result<Self, OutdatedReason> {
+        if requested < MIN_PROTOCOL_VERSION {
+            Err(OutdatedReason::Client)
+        } else {
+            Ok(Self(requested.min(PROTOCOL_VERSION)))
+        }
+    }
+
+    /// Gets the version number.
+    #[inline]
+    pub fn get(self) -> u32 {
+        self.0
+    }
+
+    /// Gets the features available in this version.
+    pub fn features(self) -> Features {
+        let mut features = Features(0);
+        if self.0 >= 10 {
+            features = features
+                | Features::PAUSE
+                | Features::POWER_UP_EFFECTS
+                | Features::MULTIPLE_POWER_UPS;
+        }
+        if self.0 >= 11 {
+            features = features | Features::POWER_UP_SETTINGS;
+        }
+        if self.0 >= 12 {
+            features = features | Features::TEAM_MASKS;
+        }
+        if self.0 >= 13 {
+            features = features | Features::ROOM_CLOSED;
+        }
+        if self.0 >= 14 {
+            features = features | Features::NEGOTIATION;
+        }
+        if self.0 >= 15 {
+            features = features | Features::DELTA_SYNC;
+        }
+        if self.0 >= 16 {
+            features = features | Features::COMPACT_STATE;
+        }
+        if self.0 >= 17 {
+            features = features | Features::TICK_RATE;
+        }
+        if self.0 >= 18 {
+            features = features | Features::LATENCY;
+        }
+        if self.0 >= 19 {
+            features = features | Features::CLOCK_SYNC;
+        }
+        if self.0 >= 20 {
+            features = features | Features::REDIRECT;
+        }
+        if self.0 >= 21 {
+            features = features | Features::ROOM_QUERY;
+        }
+        if self.0 >= 22 {
+            features = features | Features::QUICK_PLAY;
+        }
+        features
+    }
+
+    /// Checks if a feature is available in this version.
+    #[inline]
+    pub fn supports(self, feature: Features) -> bool {
+        self.features().contains(feature)
+    }
+
+    /// Encodes a message in this version.
+    /// Returns None if the message doesn't exist in this version.
+    pub fn encode(self, msg: &MessageToClient<'_>) -> Option<Bytes> {
+        let required = match msg {
+            MessageToClient::Pause(_) => Some(Features::PAUSE),
+            MessageToClient::RoomClosed => Some(Features::ROOM_CLOSED),
+            MessageToClient::Negotiated(..) => Some(Features::NEGOTIATION),
+            MessageToClient::DeltaSync(_) => Some(Features::DELTA_SYNC),
+            MessageToClient::Ping(_) | MessageToClient::Latencies(_) => Some(Features::LATENCY),
+            MessageToClient::ClockSync(_) => Some(Features::CLOCK_SYNC),
+            MessageToClient::Redirect(_) => Some(Features::REDIRECT),
+            MessageToClient::RoomPage(_) | MessageToClient::RoomListUpdate(_) => {
+                Some(Features::ROOM_QUERY)
+            }
+            MessageToClient::Queued(_) => Some(Features::QUICK_PLAY),
+            _ => None,
+        };
+        if required.is_some_and(|feature| !self.supports(feature)) {
+            return None;
+        }
+        Some(with_version(self, || {
+            crate::bincode::serialize(msg).expect("encode").into()
+        }))
+    }
+}
+
+thread_local! {
+    static CODEC_VERSION: Cell<ProtocolVersion> =
+        const { Cell::new(ProtocolVersion(MIN_PROTOCOL_VERSION)) };
+}
+
+/// Runs `f` with messages encoded and decoded in the given version.
+/// Outside of this, the oldest supported version is used.
+pub fn with_version<R>(version: ProtocolVersion, f: impl FnOnce() -> R) -> R {
+    /// Restores the version, even if `f` panics.
+    struct Reset(ProtocolVersion);
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CODEC_VERSION.with(|v| v.set(self.0));
+        }
+    }
+
+    let _reset = Reset(CODEC_VERSION.with(|v| v.replace(version)));
+    f()
+}
+
+/// Gets the version messages are currently encoded or decoded in.
+/// Types whose shape changed between versions use this to pick their format.
+#[inline]
+pub fn codec_version() -> ProtocolVersion {
+    CODEC_VERSION.with(Cell::get)
+}
+
+/// Encodes a message once per protocol version for a broadcast.
+#[derive(Default)]
+pub struct EncodeCache {
+    encoded: Vec<(ProtocolVersion, Option<Bytes>)>,
+}
+
+impl EncodeCache {
+    /// Gets the encoding of `msg` for a version, encoding it on first use.
+    pub fn get(&mut self, version: ProtocolVersion, msg: &MessageToClient<'_>) -> Option<Bytes> {
+        if let Some((_, bytes)) = self.encoded.iter().find(|(v, _)| *v == version) {
+            return bytes.clone();
+        }
+        let bytes = version.encode(msg);
+        self.encoded.push((version, bytes.clone()));
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::powerup::{PowerUp, PowerUpEffectType, PowerUpFrequency};
+    use crate::protocol::{PowerUpEvent, PowerUpPacket, SyncMessage, UpdateSettings};
+    use crate::room::TickRate;
+    use crate::shared_room_data::SharedRoomData;
+    use crate::vector::Vector;
+    use smallvec::{smallvec, SmallVec};
+
+    #[test]
+    fn negotiation_window() {
+        assert!(matches!(
+            ProtocolVersion::negotiate(MIN_PROTOCOL_VERSION - 1),
+            Err(OutdatedReason::Client)
+        ));
+        let oldest = ProtocolVersion::negotiate(MIN_PROTOCOL_VERSION).unwrap();
+        assert_eq!(oldest.get(), MIN_PROTOCOL_VERSION);
+        assert_eq!(
+            ProtocolVersion::negotiate(PROTOCOL_VERSION + 3).unwrap(),
+            ProtocolVersion::CURRENT
+        );
+
+        // Messages a version doesn't know about are not sent.
+        assert!(oldest.encode(&MessageToClient::RoomClosed).is_none());
+        assert!(ProtocolVersion::CURRENT
+            .encode(&MessageToClient::RoomClosed)
+            .is_some());
+        assert_eq!(
+            oldest.encode(&MessageToClient::Ack),
+            ProtocolVersion::CURRENT.encode(&MessageToClient::Ack)
+        );
+    }
+
+    #[test]
+    fn settings_follow_the_client_version() {
+        let mut settings = SharedRoomData::new("v".to_owned()).settings();
+        settings.tick_rate = TickRate::High;
+        settings.power_up_frequency = PowerUpFrequency::Rare;
+        let round_trip = |version| {
+            with_version(ProtocolVersion::negotiate(version).unwrap(), || {
+                let bytes = crate::bincode::serialize(&settings).unwrap();
+                let decoded: UpdateSettings = crate::bincode::deserialize(&bytes).unwrap();
+                (bytes.len(), decoded)
+            })
+        };
+
+        // The shipped client only knows the first four settings.
+        let (len, decoded) = round_trip(MIN_PROTOCOL_VERSION);
+        assert_eq!(len, 4);
+        assert!(matches!(
+            decoded.power_up_frequency,
+            PowerUpFrequency::Normal
+        ));
+        assert_eq!(decoded.tick_rate, TickRate::default());
+
+        // Older clients neither send nor receive the tick rate.
+        let (legacy_len, decoded) = round_trip(16);
+        assert!(matches!(decoded.power_up_frequency, PowerUpFrequency::Rare));
+        assert_eq!(decoded.tick_rate, TickRate::default());
+        let (len, decoded) = round_trip(PROTOCOL_VERSION);
+        assert_eq!(decoded.tick_rate, TickRate::High);
+        assert_eq!(len, legacy_len + 1);
+    }
+
+    #[test]
+    fn room_list_follows_the_client_version() {
+        let room = SharedRoomData::new("v".to_owned());
+        let size = |version| {
+            with_version(ProtocolVersion::negotiate(version).unwrap(), || {
+                crate::bincode::serialize(&room).unwrap().len()
+            })
+        };
+
+        // The set of all power-ups takes three bytes, the frequency and the tick rate one.
+        assert_eq!(size(11), size(MIN_PROTOCOL_VERSION) + 4);
+        assert_eq!(size(17), size(11) + 1);
+    }
+
+    #[test]
+    fn legacy_syncs_carry_one_power_up() {
+        let power_up = PowerUp::new(7, Vector::new(1.0, 2.0), PowerUpEffectType::BonusPoints);
+        let sync = SyncMessage {
+            frame_time: 60_000,
+            client_syncs: Vec::new(),
+            ball_syncs: SmallVec::new(),
+            power_ups: smallvec![
+                PowerUpEvent {
+                    id: 7,
+                    packet: PowerUpPacket::SpawnPowerUp(power_up),
+                },
+                PowerUpEvent {
+                    id: 7,
+                    packet: PowerUpPacket::BonusPoints(1),
+                },
+            ],
+        };
+
+        // Frame number, no client and ball syncs, and the last power-up packet without id.
+        let oldest = ProtocolVersion::negotiate(MIN_PROTOCOL_VERSION).unwrap();
+        let legacy = crate::bincode::serialize(&(3u32, 60.0f32, 0u8, 0u8, 3u32, 1u8)).unwrap();
+        assert_eq!(
+            oldest.encode(&MessageToClient::Sync(&sync)).unwrap(),
+            legacy
+        );
+        let spawn = PowerUpPacket::SpawnPowerUp(power_up);
+        let legacy_spawn = crate::bincode::serialize(&(1u32, Vector::new(1.0, 2.0), 1u32)).unwrap();
+        assert_eq!(
+            with_version(oldest, || crate::bincode::serialize(&spawn).unwrap()),
+            legacy_spawn
+        );
+    }
+}